@@ -0,0 +1,105 @@
+use crate::MediaCache;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::time::SystemTime;
+use teloxide::types::Message;
+
+// Downloaded files are kept here, until the cache grows too big
+const CACHE_DIR: &str = "/tmp/red_fetch/cache/";
+
+pub fn hash_str(s: &str) -> String {
+    format!("{:x}", Sha256::digest(s.as_bytes()))
+}
+
+// Files can be hundreds of MiB: hash them away from the async workers
+pub async fn hash_file(f: &str) -> io::Result<String> {
+    let f = f.to_string();
+    tokio::task::spawn_blocking(move || {
+        let mut hasher = Sha256::new();
+        io::copy(&mut fs::File::open(f)?, &mut hasher)?;
+        Ok(format!("{:x}", hasher.finalize()))
+    })
+    .await?
+}
+
+// Kind and file_id of the media contained in a sent message
+pub fn sent_media(msg: &Message) -> Option<(&'static str, String)> {
+    if let Some(photos) = msg.photo() {
+        // the last size is the largest one
+        return photos.last().map(|p| ("photo", p.file.id.clone()));
+    }
+    // animations are also reported as documents, check them first
+    if let Some(anim) = msg.animation() {
+        return Some(("animation", anim.file.id.clone()));
+    }
+    if let Some(video) = msg.video() {
+        return Some(("video", video.file.id.clone()));
+    }
+    if let Some(audio) = msg.audio() {
+        return Some(("audio", audio.file.id.clone()));
+    }
+    msg.document().map(|doc| ("document", doc.file.id.clone()))
+}
+
+fn disk_name(url: &str, ext: &str) -> String {
+    format!("{}{}.{}", CACHE_DIR, hash_str(url), ext)
+}
+
+// Link the cached copy of `url` (if any) to `dst`
+pub fn disk_fetch(url: &str, ext: &str, dst: &str, cache: &MediaCache) -> bool {
+    if !cache.enabled || cache.max_disk_mb == 0 {
+        return false;
+    }
+    let cached = disk_name(url, ext);
+    match fs::hard_link(&cached, dst) {
+        Ok(()) => {
+            // mark it as recently used
+            if let Ok(f) = fs::File::options().append(true).open(&cached) {
+                let _ = f.set_modified(SystemTime::now());
+            }
+            log::debug!("Reusing cached copy of {}", url);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+// Keep a copy of the file downloaded from `url`, evicting the least recently used ones
+pub async fn disk_store(url: &str, ext: &str, src: &str, cache: &MediaCache) {
+    if !cache.enabled || cache.max_disk_mb == 0 {
+        return;
+    }
+    let cached = disk_name(url, ext);
+    let src = src.to_string();
+    let max_size = cache.max_disk_mb * 1_048_576;
+    // scanning the directory blocks
+    let res = tokio::task::spawn_blocking(move || store(&src, &cached, max_size)).await;
+    if let Err(e) = res {
+        log::error!("Cannot update disk cache: {}", e);
+    }
+}
+
+fn store(src: &str, cached: &str, max_size: u64) {
+    if fs::create_dir_all(CACHE_DIR).is_err() || fs::hard_link(src, cached).is_err() {
+        return;
+    }
+    let mut files: Vec<(SystemTime, u64, _)> = match fs::read_dir(CACHE_DIR) {
+        Ok(entries) => entries
+            .flatten()
+            .filter_map(|e| {
+                let md = e.metadata().ok()?;
+                Some((md.modified().ok()?, md.len(), e.path()))
+            })
+            .collect(),
+        Err(_) => return,
+    };
+    files.sort_by_key(|(mtime, _, _)| std::cmp::Reverse(*mtime));
+    let mut size = 0;
+    for (_, len, path) in files {
+        size += len;
+        if size > max_size {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
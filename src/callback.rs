@@ -0,0 +1,198 @@
+use crate::cache;
+use crate::persist;
+use crate::reddit::RedditCmd;
+use crate::scheduler;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+// Callback data starts with the marker and the version of its encoding:
+// buttons of other versions are reported as expired
+const MARKER: char = '~';
+const VERSION: &str = "1";
+
+// Telegram rejects longer callback data
+const MAX_LEN: usize = 64;
+
+// Actions stored in the lookup table are kept this long
+pub const EXPIRY_DAYS: i64 = 30;
+
+// What a button does, whatever the state of the dialogue
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Action {
+    // show the subreddits of a category
+    #[serde(rename = "c")]
+    Category(String),
+    // show the views of a subreddit: category and subreddit
+    #[serde(rename = "s")]
+    Subreddit(String, String),
+    // ask for the number of posts
+    #[serde(rename = "v")]
+    View(RedditCmd),
+    // send the first page
+    #[serde(rename = "t")]
+    Tot(RedditCmd),
+    #[serde(rename = "n")]
+    Next(RedditCmd),
+    #[serde(rename = "b")]
+    Subscribe(RedditCmd),
+    // save a subscription with the schedule
+    #[serde(rename = "h")]
+    Schedule(RedditCmd, String),
+    // remove a subscription by id
+    #[serde(rename = "u")]
+    Unsubscribe(i64),
+    // cycle through the values of a setting
+    #[serde(rename = "o")]
+    Setting(String),
+    // go on with a search, after choosing one of its options
+    #[serde(rename = "q")]
+    Search(RedditCmd),
+    // ask which category to add a subreddit to
+    #[serde(rename = "p")]
+    PickCategory(String),
+    // add a subreddit to a category: subreddit and category
+    #[serde(rename = "a")]
+    AddTo(String, String),
+    // show a page of the comments of a post: post id and page number
+    #[serde(rename = "m")]
+    Comments(String, usize),
+    #[serde(rename = "d")]
+    Done,
+}
+
+pub fn is_action(data: &str) -> bool {
+    data.starts_with(MARKER)
+}
+
+// Compact actions are embedded in the data, the others are referenced by key
+pub async fn encode(db: &SqlitePool, action: &Action) -> Result<String> {
+    let json = serde_json::to_string(action)?;
+    let data = format!("{}{}:{}", MARKER, VERSION, json);
+    if data.len() <= MAX_LEN {
+        return Ok(data);
+    }
+    let key = &cache::hash_str(&json)[..16];
+    persist::insert_callback(db, key, &json, scheduler::now()).await?;
+    Ok(format!("{}{}#{}", MARKER, VERSION, key))
+}
+
+// None if the button is of another version, or its action has been pruned
+pub async fn decode(db: &SqlitePool, data: &str) -> Result<Option<Action>> {
+    let Some(rest) = data
+        .strip_prefix(MARKER)
+        .and_then(|d| d.strip_prefix(VERSION))
+    else {
+        return Ok(None);
+    };
+    let json = if let Some(json) = rest.strip_prefix(':') {
+        json.to_string()
+    } else if let Some(key) = rest.strip_prefix('#') {
+        match persist::get_callback(db, key).await? {
+            Some(json) => json,
+            None => return Ok(None),
+        }
+    } else {
+        return Ok(None);
+    };
+    match serde_json::from_str(&json) {
+        Ok(action) => Ok(Some(action)),
+        Err(e) => {
+            log::info!("Cannot parse callback data {}: {}", data, e);
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reddit::{Period, RedReq};
+
+    fn rcmd() -> RedditCmd {
+        RedditCmd {
+            view: RedReq::Top(Period::W),
+            subreddit: "rust".to_string(),
+            tot: 5,
+            category: "Programming".to_string(),
+            last_seen: Some("t3_abc".to_string()),
+            search: None,
+        }
+    }
+
+    async fn round_trip(db: &SqlitePool, action: Action) {
+        let data = encode(db, &action).await.unwrap();
+        assert!(data.len() <= MAX_LEN, "{}", data);
+        assert!(is_action(&data));
+        let decoded = decode(db, &data).await.unwrap().expect(&data);
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", action));
+    }
+
+    #[tokio::test]
+    async fn round_trips() {
+        let db = persist::memory_db().await;
+        let actions = [
+            Action::Category("News".to_string()),
+            Action::Subreddit("News".to_string(), "worldnews".to_string()),
+            Action::View(rcmd()),
+            Action::Tot(rcmd()),
+            Action::Next(rcmd()),
+            Action::Subscribe(rcmd()),
+            Action::Schedule(rcmd(), "daily 08:30".to_string()),
+            Action::Unsubscribe(42),
+            Action::Setting("skip_seen".to_string()),
+            Action::Search(rcmd()),
+            Action::PickCategory("rust".to_string()),
+            Action::AddTo("rust".to_string(), "Programming".to_string()),
+            Action::Comments("abc123".to_string(), 2),
+            Action::Done,
+        ];
+        for action in actions {
+            round_trip(&db, action).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn long_actions_are_stored() {
+        let db = persist::memory_db().await;
+        let empty = Action::Category(String::new());
+        let overhead = encode(&db, &empty).await.unwrap().len();
+        // the longest action which fits in the data
+        let fits = Action::Category("x".repeat(MAX_LEN - overhead));
+        let data = encode(&db, &fits).await.unwrap();
+        assert_eq!(data.len(), MAX_LEN);
+        assert!(data.starts_with("~1:"));
+        round_trip(&db, fits).await;
+        // one more byte, and it is referenced by key
+        let stored = Action::Category("x".repeat(MAX_LEN - overhead + 1));
+        let data = encode(&db, &stored).await.unwrap();
+        assert!(data.starts_with("~1#"));
+        round_trip(&db, stored).await;
+    }
+
+    #[tokio::test]
+    async fn bad_data() {
+        let db = persist::memory_db().await;
+        let bad = [
+            "",
+            "Comments abc123",
+            "~1",
+            "~1x{\"d\"}",
+            "~1:{\"c\":",
+            "~1:{\"z\":\"News\"}",
+            "~0:\"d\"",
+            "~2:\"d\"",
+        ];
+        for data in bad {
+            assert!(decode(&db, data).await.unwrap().is_none(), "{}", data);
+        }
+        assert!(decode(&db, "~1:\"d\"").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn missing_key() {
+        let db = persist::memory_db().await;
+        let data = "~1#0123456789abcdef";
+        assert!(decode(&db, data).await.unwrap().is_none());
+    }
+}
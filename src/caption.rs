@@ -0,0 +1,217 @@
+use crate::markdown;
+use crate::scheduler;
+use roux::submission::SubmissionData;
+
+// Fields which can be used in caption templates, as {name}
+pub const FIELDS: [&str; 11] = [
+    "title",
+    "score",
+    "upvote_ratio",
+    "num_comments",
+    "author",
+    "flair",
+    "subreddit",
+    "age",
+    "permalink",
+    "url",
+    "domain",
+];
+
+// Telegram allows 1024 characters, leave some room for notes
+pub const MAX_LEN: usize = 1000;
+
+pub fn default_template() -> String {
+    "{title}".to_string()
+}
+
+// Compact age of a post, e.g. "5h"
+fn age(created_utc: f64) -> String {
+    let secs = (scheduler::now() - created_utc as i64).max(0);
+    match secs {
+        s if s < 3600 => format!("{}m", s / 60),
+        s if s < 86400 => format!("{}h", s / 3600),
+        s => format!("{}d", s / 86400),
+    }
+}
+
+pub fn fields(post: &SubmissionData) -> Vec<(&'static str, String)> {
+    vec![
+        ("title", post.title.clone()),
+        ("score", post.score.to_string()),
+        ("upvote_ratio", format!("{:.0}%", post.upvote_ratio * 100.0)),
+        ("num_comments", post.num_comments.to_string()),
+        ("author", post.author.clone()),
+        ("flair", post.link_flair_text.clone().unwrap_or_default()),
+        ("subreddit", post.subreddit.clone()),
+        ("age", age(post.created_utc)),
+        (
+            "permalink",
+            format!("https://www.reddit.com{}", post.permalink),
+        ),
+        ("url", post.url.clone().unwrap_or_default()),
+        ("domain", post.domain.clone().unwrap_or_default()),
+    ]
+}
+
+// Made-up fields, to preview a template
+pub fn sample_fields() -> Vec<(&'static str, String)> {
+    let values = [
+        "Rust 1.0 released <3",
+        "1234",
+        "97%",
+        "321",
+        "ferris",
+        "News",
+        "rust",
+        "5h",
+        "https://www.reddit.com/r/rust/comments/abc123/rust_10_released/",
+        "https://blog.rust-lang.org/",
+        "blog.rust-lang.org",
+    ];
+    FIELDS
+        .iter()
+        .zip(values)
+        .map(|(k, v)| (*k, v.to_string()))
+        .collect()
+}
+
+// Replace the {name} placeholders of the (HTML) template with the escaped fields
+fn fill(template: &str, fields: &[(&str, String)]) -> String {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = match rest.find('}') {
+            Some(end) => end,
+            None => break,
+        };
+        let key = &rest[1..end];
+        // e.g. "{ {title}": the first brace is text
+        if key.contains('{') {
+            out.push('{');
+            rest = &rest[1..];
+            continue;
+        }
+        match fields.iter().find(|(k, _)| *k == key) {
+            Some((_, v)) => out.push_str(&markdown::escape(v)),
+            None => out.push_str(&rest[..=end]),
+        }
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+// At most max_len UTF-16 code units of s
+fn truncate(s: &str, max_len: usize) -> String {
+    let mut len = 0;
+    s.chars()
+        .take_while(|c| {
+            len += c.len_utf16();
+            len <= max_len
+        })
+        .collect()
+}
+
+// Render the template, shortening the title if the caption exceeds max_len
+pub fn render(template: &str, fields: &[(&'static str, String)], max_len: usize) -> String {
+    let html = fill(template, fields);
+    let excess = markdown::text_len(&html).saturating_sub(max_len);
+    if excess == 0 {
+        return html;
+    }
+    let title = fields
+        .iter()
+        .find(|(k, _)| *k == "title")
+        .map(|(_, v)| v.as_str())
+        .unwrap_or_default();
+    let title_len = title.encode_utf16().count();
+    let mut short = truncate(title, title_len.saturating_sub(excess + 1));
+    short.push('\u{2026}');
+    let fields: Vec<(&str, String)> = fields
+        .iter()
+        .map(|(k, v)| match *k {
+            "title" => (*k, short.clone()),
+            _ => (*k, v.clone()),
+        })
+        .collect();
+    let html = fill(template, &fields);
+    if markdown::text_len(&html) <= max_len {
+        html
+    } else {
+        // the template itself is too long
+        markdown::escape(&truncate(title, max_len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(title: &str) -> Vec<(&'static str, String)> {
+        sample_fields()
+            .into_iter()
+            .map(|(k, v)| match k {
+                "title" => (k, title.to_string()),
+                _ => (k, v),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fills_fields() {
+        let fields = sample("Hello");
+        let html = render("<b>{title}</b> ({score}, r/{subreddit})", &fields, MAX_LEN);
+        assert_eq!(html, "<b>Hello</b> (1234, r/rust)");
+    }
+
+    #[test]
+    fn unknown_fields() {
+        let fields = sample("Hello");
+        assert_eq!(render("{nope} {title}", &fields, MAX_LEN), "{nope} Hello");
+        assert_eq!(render("{}", &fields, MAX_LEN), "{}");
+    }
+
+    #[test]
+    fn unclosed_braces() {
+        let fields = sample("Hello");
+        assert_eq!(render("{title", &fields, MAX_LEN), "{title");
+        assert_eq!(render("{title} {", &fields, MAX_LEN), "Hello {");
+        assert_eq!(render("{ {title}", &fields, MAX_LEN), "{ Hello");
+        assert_eq!(render("}{title}", &fields, MAX_LEN), "}Hello");
+    }
+
+    #[test]
+    fn escapes_fields() {
+        let fields = sample("Rust <3 & C++");
+        assert_eq!(
+            render("<i>{title}</i>", &fields, MAX_LEN),
+            "<i>Rust &lt;3 &amp; C++</i>"
+        );
+    }
+
+    #[test]
+    fn shortens_title() {
+        let fields = sample(&"é".repeat(50));
+        let html = render("<b>{title}</b> {score}", &fields, 20);
+        assert_eq!(markdown::text_len(&html), 20);
+        assert_eq!(html, format!("<b>{}\u{2026}</b> 1234", "é".repeat(14)));
+    }
+
+    #[test]
+    fn shortens_astral_title() {
+        // each emoji takes two UTF-16 code units
+        let fields = sample(&"\u{1F980}".repeat(30));
+        let html = render("{title}!", &fields, 20);
+        assert!(markdown::text_len(&html) <= 20);
+        assert_eq!(html, format!("{}\u{2026}!", "\u{1F980}".repeat(9)));
+    }
+
+    #[test]
+    fn template_too_long() {
+        let fields = sample("Hello & bye");
+        let html = render(&"x".repeat(30), &fields, 8);
+        assert_eq!(html, "Hello &amp; ");
+    }
+}
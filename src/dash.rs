@@ -0,0 +1,254 @@
+use crate::transcode;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use std::collections::HashMap;
+use url::Url;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Track {
+    Video,
+    Audio,
+}
+
+// A stream listed in a DASH playlist
+#[derive(Clone, Debug)]
+pub struct Representation {
+    pub track: Track,
+    pub bandwidth: u64,
+    pub url: String,
+}
+
+fn track_of(attrs: &HashMap<String, String>) -> Option<Track> {
+    let ctype = attrs.get("contentType").map(|s| s.as_str());
+    let mime = attrs
+        .get("mimeType")
+        .map(|s| s.as_str())
+        .unwrap_or_default();
+    if ctype == Some("video") || mime.starts_with("video/") {
+        Some(Track::Video)
+    } else if ctype == Some("audio") || mime.starts_with("audio/") {
+        Some(Track::Audio)
+    } else {
+        None
+    }
+}
+
+// Extract the streams of an MPD playlist, resolving their URLs against `base`
+pub fn parse_mpd(xml: &str, base: &Url) -> Vec<Representation> {
+    let mut reader = Reader::from_str(xml);
+    reader.trim_text(true);
+    let mut reps = Vec::new();
+    let mut set_track = None; // track type of the current AdaptationSet
+    let mut cur: Option<(Option<Track>, u64, Option<String>)> = None;
+    let mut in_base = false;
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) | Ok(Event::Empty(e)) => {
+                let attrs: HashMap<String, String> = e
+                    .attributes()
+                    .flatten()
+                    .map(|a| {
+                        let key = String::from_utf8_lossy(a.key.as_ref()).to_string();
+                        let val = a.unescape_value().unwrap_or_default().to_string();
+                        (key, val)
+                    })
+                    .collect();
+                match e.name().as_ref() {
+                    b"AdaptationSet" => set_track = track_of(&attrs),
+                    b"Representation" => {
+                        let track = track_of(&attrs).or_else(|| set_track.clone());
+                        let bandwidth = attrs
+                            .get("bandwidth")
+                            .and_then(|b| b.parse().ok())
+                            .unwrap_or(0);
+                        cur = Some((track, bandwidth, None));
+                    }
+                    b"BaseURL" => in_base = true,
+                    _ => {}
+                }
+            }
+            Ok(Event::Text(t)) if in_base => {
+                if let (Some(rep), Ok(txt)) = (cur.as_mut(), t.unescape()) {
+                    rep.2 = base.join(&txt).ok().map(|u| u.to_string());
+                }
+            }
+            Ok(Event::End(e)) => match e.name().as_ref() {
+                b"BaseURL" => in_base = false,
+                b"AdaptationSet" => set_track = None,
+                b"Representation" => {
+                    if let Some((Some(track), bandwidth, Some(url))) = cur.take() {
+                        reps.push(Representation {
+                            track,
+                            bandwidth,
+                            url,
+                        });
+                    }
+                }
+                _ => {}
+            },
+            Ok(Event::Eof) => break,
+            Err(e) => {
+                log::info!("Cannot parse DASH playlist: {}", e);
+                break;
+            }
+            _ => {}
+        }
+    }
+    reps
+}
+
+// The video streams (best first) which, with the best audio stream, fit in max_size bytes.
+// Sizes are estimated from the bitrates and the duration (in seconds).
+pub fn pick(
+    reps: &[Representation],
+    duration: f64,
+    max_size: u64,
+) -> (Vec<&Representation>, Option<&Representation>) {
+    let audio = reps
+        .iter()
+        .filter(|r| r.track == Track::Audio)
+        .max_by_key(|r| r.bandwidth);
+    let est_size = |bandwidth: u64| bandwidth as f64 * duration / 8.0;
+    let audio_size = audio.map_or(0.0, |a| est_size(a.bandwidth));
+    let mut videos: Vec<_> = reps
+        .iter()
+        .filter(|r| r.track == Track::Video)
+        .filter(|v| est_size(v.bandwidth) + audio_size <= max_size as f64)
+        .collect();
+    videos.sort_by_key(|r| std::cmp::Reverse(r.bandwidth));
+    (videos, audio)
+}
+
+// Merge video and audio streams with ffmpeg, if available
+pub async fn mux(video: &str, audio: &str, out: &str) -> bool {
+    let args = [
+        "-i",
+        video,
+        "-i",
+        audio,
+        "-c",
+        "copy",
+        "-movflags",
+        "+faststart",
+        out,
+    ];
+    transcode::ffmpeg(&args).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // as served by v.redd.it, with the streams in no particular order
+    const MPD: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT10S">
+  <Period duration="PT10S">
+    <AdaptationSet contentType="video" segmentAlignment="true">
+      <Representation id="2" bandwidth="1200000" codecs="avc1.4d401f" width="480" height="480">
+        <BaseURL>DASH_480.mp4</BaseURL>
+      </Representation>
+      <Representation id="3" bandwidth="4800000" codecs="avc1.4d401f" width="1080" height="1080">
+        <BaseURL>DASH_1080.mp4?source=fallback</BaseURL>
+      </Representation>
+      <Representation id="1" bandwidth="2400000" codecs="avc1.4d401f" width="720" height="720">
+        <BaseURL>https://cdn.example.com/DASH_720.mp4</BaseURL>
+      </Representation>
+    </AdaptationSet>
+    <AdaptationSet contentType="audio">
+      <Representation id="5" bandwidth="64000" codecs="mp4a.40.2">
+        <BaseURL>DASH_AUDIO_64.mp4</BaseURL>
+      </Representation>
+      <Representation id="6" bandwidth="128000" codecs="mp4a.40.2">
+        <BaseURL>DASH_AUDIO_128.mp4</BaseURL>
+      </Representation>
+    </AdaptationSet>
+    <AdaptationSet>
+      <Representation id="7" mimeType="video/mp4" bandwidth="100000">
+        <BaseURL>DASH_96.mp4</BaseURL>
+      </Representation>
+      <Representation id="8" bandwidth="1000">
+        <BaseURL>unknown.bin</BaseURL>
+      </Representation>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+
+    fn reps() -> Vec<Representation> {
+        let base = Url::parse("https://v.redd.it/abc123/DASHPlaylist.mpd?a=1").unwrap();
+        parse_mpd(MPD, &base)
+    }
+
+    #[test]
+    fn parses_streams() {
+        let reps = reps();
+        let urls: Vec<(&Track, u64, &str)> = reps
+            .iter()
+            .map(|r| (&r.track, r.bandwidth, r.url.as_str()))
+            .collect();
+        assert_eq!(
+            urls,
+            [
+                (
+                    &Track::Video,
+                    1200000,
+                    "https://v.redd.it/abc123/DASH_480.mp4"
+                ),
+                (
+                    &Track::Video,
+                    4800000,
+                    "https://v.redd.it/abc123/DASH_1080.mp4?source=fallback"
+                ),
+                (
+                    &Track::Video,
+                    2400000,
+                    "https://cdn.example.com/DASH_720.mp4"
+                ),
+                (
+                    &Track::Audio,
+                    64000,
+                    "https://v.redd.it/abc123/DASH_AUDIO_64.mp4"
+                ),
+                (
+                    &Track::Audio,
+                    128000,
+                    "https://v.redd.it/abc123/DASH_AUDIO_128.mp4"
+                ),
+                // the type can also be given by the stream
+                (
+                    &Track::Video,
+                    100000,
+                    "https://v.redd.it/abc123/DASH_96.mp4"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn picks_best_streams() {
+        let reps = reps();
+        let (videos, audio) = pick(&reps, 10.0, 100 * 1_048_576);
+        assert_eq!(audio.unwrap().bandwidth, 128000);
+        let bandwidths: Vec<u64> = videos.iter().map(|v| v.bandwidth).collect();
+        assert_eq!(bandwidths, [4800000, 2400000, 1200000, 100000]);
+    }
+
+    #[test]
+    fn picks_fitting_streams() {
+        let reps = reps();
+        // 10 s of 2.4 Mb/s video and 128 kb/s audio take 3.16 MB
+        let (videos, _) = pick(&reps, 10.0, 3_160_000);
+        let bandwidths: Vec<u64> = videos.iter().map(|v| v.bandwidth).collect();
+        assert_eq!(bandwidths, [2400000, 1200000, 100000]);
+        let (videos, _) = pick(&reps, 10.0, 3_159_999);
+        assert_eq!(videos[0].bandwidth, 1200000);
+        let (videos, _) = pick(&reps, 10.0, 1000);
+        assert!(videos.is_empty());
+    }
+
+    #[test]
+    fn bad_playlist() {
+        let base = Url::parse("https://v.redd.it/abc123/").unwrap();
+        assert!(parse_mpd("", &base).is_empty());
+        assert!(parse_mpd("<MPD><Period><AdaptationSet", &base).is_empty());
+    }
+}
@@ -0,0 +1,261 @@
+use regex::{Regex, RegexBuilder};
+use roux::submission::SubmissionData;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+// Rules which posts must satisfy to be sent (empty rules accept everything)
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Filters {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_score: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_upvote_ratio: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_comments: Option<u64>,
+    // regexes, matched against the title
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub include_keywords: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub exclude_keywords: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub include_flairs: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub exclude_flairs: Vec<String>,
+    // domains also match their subdomains
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub allowed_domains: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub blocked_domains: Vec<String>,
+}
+
+pub const RULES: [&str; 9] = [
+    "min_score",
+    "min_upvote_ratio",
+    "min_comments",
+    "include_keywords",
+    "exclude_keywords",
+    "include_flairs",
+    "exclude_flairs",
+    "allowed_domains",
+    "blocked_domains",
+];
+
+// At most one number, none to clear the rule
+fn number<T: FromStr>(values: &[&str]) -> Result<Option<T>, String> {
+    match values {
+        [] => Ok(None),
+        [v] => v
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("Invalid number: {}", v)),
+        _ => Err("Please specify a single number.".to_string()),
+    }
+}
+
+fn regexes(list: &[String]) -> Result<Vec<Regex>, regex::Error> {
+    list.iter()
+        .map(|k| RegexBuilder::new(k).case_insensitive(true).build())
+        .collect()
+}
+
+impl Filters {
+    pub fn is_empty(&self) -> bool {
+        *self == Filters::default()
+    }
+    // Set (or, without values, clear) a rule
+    pub fn set(&mut self, rule: &str, values: &[&str]) -> Result<(), String> {
+        let list: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+        match rule {
+            "min_score" => self.min_score = number(values)?,
+            "min_upvote_ratio" => self.min_upvote_ratio = number(values)?,
+            "min_comments" => self.min_comments = number(values)?,
+            "include_keywords" => self.include_keywords = list,
+            "exclude_keywords" => self.exclude_keywords = list,
+            "include_flairs" => self.include_flairs = list,
+            "exclude_flairs" => self.exclude_flairs = list,
+            "allowed_domains" => self.allowed_domains = list,
+            "blocked_domains" => self.blocked_domains = list,
+            _ => return Err(format!("Unknown rule: {}", rule)),
+        }
+        self.compile().map(|_| ()).map_err(|e| e.to_string())
+    }
+    pub fn compile(&self) -> Result<Filter, regex::Error> {
+        Ok(Filter {
+            include: regexes(&self.include_keywords)?,
+            exclude: regexes(&self.exclude_keywords)?,
+            rules: self.clone(),
+        })
+    }
+}
+
+impl fmt::Display for Filters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "none");
+        }
+        let json = serde_json::to_value(self).map_err(|_| fmt::Error)?;
+        let mut lines = Vec::new();
+        for (rule, val) in json.as_object().into_iter().flatten() {
+            let val = match val {
+                serde_json::Value::Array(list) => {
+                    let list: Vec<&str> = list.iter().filter_map(|v| v.as_str()).collect();
+                    list.join(", ")
+                }
+                _ => val.to_string(),
+            };
+            lines.push(format!("{}: {}", rule, val));
+        }
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+// Filters with compiled regexes
+pub struct Filter {
+    rules: Filters,
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+}
+
+fn has_domain(domain: &str, list: &[String]) -> bool {
+    list.iter().any(|d| {
+        let d = d.to_lowercase();
+        domain == d || domain.ends_with(&format!(".{}", d))
+    })
+}
+
+impl Filter {
+    pub fn accepts(&self, post: &SubmissionData) -> bool {
+        let rules = &self.rules;
+        if rules.min_score.is_some_and(|min| (post.score as i64) < min)
+            || rules
+                .min_upvote_ratio
+                .is_some_and(|min| post.upvote_ratio < min)
+            || rules
+                .min_comments
+                .is_some_and(|min| post.num_comments < min)
+        {
+            return false;
+        }
+        let title = &post.title;
+        if !self.include.is_empty() && !self.include.iter().any(|r| r.is_match(title)) {
+            return false;
+        }
+        if self.exclude.iter().any(|r| r.is_match(title)) {
+            return false;
+        }
+        let flair = post.link_flair_text.as_deref().unwrap_or_default();
+        let has_flair = |list: &[String]| list.iter().any(|f| f.eq_ignore_ascii_case(flair));
+        if !rules.include_flairs.is_empty() && !has_flair(&rules.include_flairs) {
+            return false;
+        }
+        if has_flair(&rules.exclude_flairs) {
+            return false;
+        }
+        let domain = post.domain.as_deref().unwrap_or_default().to_lowercase();
+        if !rules.allowed_domains.is_empty() && !has_domain(&domain, &rules.allowed_domains) {
+            return false;
+        }
+        !has_domain(&domain, &rules.blocked_domains)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reddit::test_post;
+    use serde_json::json;
+
+    fn filter(rules: &[(&str, &[&str])]) -> Filter {
+        let mut filters = Filters::default();
+        for (rule, values) in rules {
+            filters.set(rule, values).unwrap();
+        }
+        filters.compile().unwrap()
+    }
+
+    fn accepts(filter: &Filter, fields: serde_json::Value) -> bool {
+        filter.accepts(&test_post(fields).data.base)
+    }
+
+    #[test]
+    fn empty_accepts_everything() {
+        let filters = Filters::default();
+        assert!(filters.is_empty());
+        assert!(accepts(&filters.compile().unwrap(), json!({})));
+    }
+
+    #[test]
+    fn thresholds() {
+        let f = filter(&[
+            ("min_score", &["50"]),
+            ("min_upvote_ratio", &["0.8"]),
+            ("min_comments", &["5"]),
+        ]);
+        let ok = json!({"score": 50.0, "upvote_ratio": 0.8, "num_comments": 5});
+        assert!(accepts(&f, ok));
+        assert!(!accepts(&f, json!({"score": 49.0})));
+        assert!(!accepts(&f, json!({"upvote_ratio": 0.79})));
+        assert!(!accepts(&f, json!({"num_comments": 4})));
+    }
+
+    #[test]
+    fn set_and_clear() {
+        let mut filters = Filters::default();
+        filters.set("min_score", &["10"]).unwrap();
+        assert_eq!(filters.min_score, Some(10));
+        filters.set("min_score", &[]).unwrap();
+        assert!(filters.is_empty());
+        assert!(filters.set("min_score", &["ten"]).is_err());
+        assert!(filters.set("min_score", &["1", "2"]).is_err());
+        assert!(filters.set("max_score", &["1"]).is_err());
+    }
+
+    #[test]
+    fn keywords() {
+        let f = filter(&[
+            ("include_keywords", &["rust", "^release"]),
+            ("exclude_keywords", &["meme"]),
+        ]);
+        assert!(accepts(&f, json!({"title": "Learning RUST"})));
+        assert!(accepts(&f, json!({"title": "Release notes"})));
+        assert!(!accepts(&f, json!({"title": "Pre-release notes"})));
+        assert!(!accepts(&f, json!({"title": "A rust MEME"})));
+        assert!(!accepts(&f, json!({"title": "Something else"})));
+    }
+
+    #[test]
+    fn bad_regex() {
+        let mut filters = Filters::default();
+        assert!(filters.set("include_keywords", &["(unclosed"]).is_err());
+        filters.exclude_keywords = vec!["[a-".to_string()];
+        assert!(filters.compile().is_err());
+    }
+
+    #[test]
+    fn flairs() {
+        let f = filter(&[("include_flairs", &["News", "Discussion"])]);
+        assert!(accepts(&f, json!({"link_flair_text": "news"})));
+        assert!(!accepts(&f, json!({"link_flair_text": "Meme"})));
+        assert!(!accepts(&f, json!({})));
+        let f = filter(&[("exclude_flairs", &["Meme"])]);
+        assert!(!accepts(&f, json!({"link_flair_text": "MEME"})));
+        assert!(accepts(&f, json!({"link_flair_text": "News"})));
+        assert!(accepts(&f, json!({})));
+    }
+
+    #[test]
+    fn domains() {
+        let f = filter(&[("allowed_domains", &["YouTube.com", "i.redd.it"])]);
+        assert!(accepts(&f, json!({"domain": "youtube.com"})));
+        assert!(accepts(&f, json!({"domain": "m.youtube.com"})));
+        assert!(accepts(&f, json!({"domain": "i.redd.it"})));
+        assert!(!accepts(&f, json!({"domain": "notyoutube.com"})));
+        assert!(!accepts(&f, json!({"domain": "v.redd.it"})));
+        let f = filter(&[("blocked_domains", &["twitter.com"])]);
+        assert!(!accepts(&f, json!({"domain": "mobile.twitter.com"})));
+        assert!(accepts(&f, json!({"domain": "twitter.company.com"})));
+        assert!(accepts(&f, json!({})));
+    }
+}
@@ -1,15 +1,57 @@
+use filters::Filters;
 use serde::{Deserialize, Serialize};
 use sqlx::SqlitePool;
 use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::sync::Arc;
-use teloxide::{dispatching::dialogue::InMemStorage, prelude::*};
+use strum_macros::{Display, EnumString};
+use teloxide::prelude::*;
 
+mod cache;
+mod callback;
+mod caption;
+mod dash;
+mod filters;
+mod markdown;
 mod persist;
 mod reddit;
+mod scheduler;
 mod telegram;
+mod topic;
+mod transcode;
+mod watcher;
 
 pub type SubredditsCats = HashMap<String, Vec<String>>;
+// JSON file exchanged via /getsubs and /sendsubs: the filters are the ones of the chat,
+// the subscriptions (and so their filters) are not included
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SubsFile {
+    #[serde(default, skip_serializing_if = "Filters::is_empty")]
+    filters: Filters,
+    subreddits: SubredditsCats,
+}
+// Older files listed the categories at the top level, next to the filters
+#[derive(Deserialize)]
+struct LegacySubsFile {
+    #[serde(default)]
+    filters: Filters,
+    #[serde(flatten)]
+    subreddits: SubredditsCats,
+}
+
+impl SubsFile {
+    pub fn parse(txt: &str) -> Result<SubsFile, serde_json::Error> {
+        let value: serde_json::Value = serde_json::from_str(txt)?;
+        if value.get("subreddits").is_some_and(|v| v.is_object()) {
+            return serde_json::from_value(value);
+        }
+        let legacy: LegacySubsFile = serde_json::from_value(value)?;
+        Ok(SubsFile {
+            filters: legacy.filters,
+            subreddits: legacy.subreddits,
+        })
+    }
+}
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct StartEnd {
     starts: Vec<String>,
@@ -17,14 +59,57 @@ pub struct StartEnd {
 }
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct UrlMatches {
-    image: StartEnd,
     video: StartEnd,
 }
 #[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ImageStep {
+    max_side: u32,
+    quality: u32,
+}
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VideoStep {
+    height: u32,
+    video_kbps: u32,
+    audio_kbps: u32,
+}
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct MediaPipeline {
+    enabled: bool,
+    max_download_mb: u64,
+    image_ladder: Vec<ImageStep>,
+    video_ladder: Vec<VideoStep>,
+}
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct MediaCache {
+    enabled: bool,
+    expiry_days: u64,
+    max_disk_mb: u64,
+}
+// How NSFW and spoiler posts are shown
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, Display, EnumString)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum NsfwPolicy {
+    Hide,
+    #[default]
+    Blur,
+    Show,
+}
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct MyBotConfig {
     cat_subreddits: SubredditsCats,
     id_whitelist: HashSet<ChatId>,
     url_matches: UrlMatches,
+    #[serde(default)]
+    media_pipeline: MediaPipeline,
+    #[serde(default)]
+    media_cache: MediaCache,
+    #[serde(default = "caption::default_template")]
+    caption_template: String,
+    #[serde(default)]
+    nsfw_policy: NsfwPolicy,
+    #[serde(default = "watcher::default_interval")]
+    watch_interval_mins: u64,
 }
 
 #[derive(Clone, Debug)]
@@ -50,11 +135,49 @@ async fn main() {
     let my_conf = get_conf();
     log::debug!("{my_conf:?}");
     let db = persist::open_db().await.expect("Cannot open DB");
+    let storage = persist::DialogueStorage::new(db.clone());
     let my_state = Arc::new(MyState { my_conf, db });
-    Dispatcher::builder(bot, telegram::schema(my_state))
-        .dependencies(dptree::deps![InMemStorage::<telegram::State>::new()])
+    tokio::spawn(scheduler::run(bot.clone(), my_state.clone()));
+    tokio::spawn(watcher::run(bot.clone(), my_state.clone()));
+    Dispatcher::builder(bot, telegram::schema(my_state.clone()))
+        .dependencies(dptree::deps![storage, my_state])
         .enable_ctrlc_handler()
         .build()
         .dispatch()
         .await;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subs_file_filters_category() {
+        let txt = r#"{"filters": {"min_score": 10}, "subreddits": {"filters": ["coffee"]}}"#;
+        let subs = SubsFile::parse(txt).unwrap();
+        assert_eq!(subs.filters.min_score, Some(10));
+        assert_eq!(subs.subreddits["filters"], vec!["coffee"]);
+    }
+
+    #[test]
+    fn subs_file_round_trips() {
+        let mut subreddits = SubredditsCats::new();
+        subreddits.insert("filters".to_string(), vec!["coffee".to_string()]);
+        let subs = SubsFile {
+            filters: Filters::default(),
+            subreddits,
+        };
+        let txt = serde_json::to_string(&subs).unwrap();
+        assert_eq!(SubsFile::parse(&txt).unwrap().subreddits, subs.subreddits);
+    }
+
+    #[test]
+    fn subs_file_legacy() {
+        let txt = r#"{"filters": {"min_score": 10}, "News": ["worldnews"]}"#;
+        let subs = SubsFile::parse(txt).unwrap();
+        assert_eq!(subs.filters.min_score, Some(10));
+        assert_eq!(subs.subreddits["News"], vec!["worldnews"]);
+        let subs = SubsFile::parse(r#"{"News": ["worldnews"]}"#).unwrap();
+        assert!(subs.filters.is_empty());
+    }
+}
@@ -0,0 +1,538 @@
+// Conversion of Reddit markdown to the HTML subset accepted by Telegram
+
+#[derive(Debug)]
+enum Block {
+    Code(String),
+    Quote(String),
+    Heading(String),
+    Rule,
+    Text(String),
+}
+
+impl Block {
+    fn get_s(&self) -> &str {
+        match self {
+            Block::Code(s) | Block::Quote(s) | Block::Heading(s) | Block::Text(s) => s,
+            Block::Rule => "",
+        }
+    }
+    // same kind of block, containing s
+    fn with_s(&self, s: String) -> Block {
+        match self {
+            Block::Code(_) => Block::Code(s),
+            Block::Quote(_) => Block::Quote(s),
+            Block::Heading(_) => Block::Heading(s),
+            Block::Rule => Block::Rule,
+            Block::Text(_) => Block::Text(s),
+        }
+    }
+    fn render(&self, spoiler: bool) -> String {
+        let hide = |html: String| match spoiler {
+            true => spoil(&html),
+            false => html,
+        };
+        match self {
+            // spoilers cannot contain code blocks: hide the plain text instead
+            Block::Code(s) if spoiler => spoil(&escape(s)),
+            Block::Code(s) => format!("<pre>{}</pre>", escape(s)),
+            Block::Quote(s) => format!("<blockquote>{}</blockquote>", hide(inline(s))),
+            Block::Heading(s) => format!("<b>{}</b>", hide(inline(s))),
+            Block::Rule => "\u{2014}".repeat(10),
+            Block::Text(s) => hide(inline(s)),
+        }
+    }
+}
+
+// Hide inline HTML behind a spoiler, which can neither contain code nor other spoilers
+fn spoil(html: &str) -> String {
+    let inner = ["<code>", "</code>", "<tg-spoiler>", "</tg-spoiler>"]
+        .iter()
+        .fold(html.to_string(), |h, tag| h.replace(tag, ""));
+    format!("<tg-spoiler>{}</tg-spoiler>", inner)
+}
+
+pub fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Reddit stores some entities in the markdown source
+fn unescape(s: &str) -> String {
+    s.replace("&#x200B;", "")
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+// Length of the text shown by Telegram, in UTF-16 code units (as counted by the API)
+pub fn text_len(html: &str) -> usize {
+    let mut len = 0;
+    let mut in_tag = false;
+    let mut in_entity = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            _ if in_tag => {}
+            '&' => {
+                in_entity = true;
+                len += 1;
+            }
+            ';' if in_entity => in_entity = false,
+            _ if in_entity => {}
+            _ => len += c.len_utf16(),
+        }
+    }
+    len
+}
+
+fn starts(chars: &[char], i: usize, pat: &str) -> bool {
+    pat.chars()
+        .enumerate()
+        .all(|(k, c)| chars.get(i + k) == Some(&c))
+}
+
+fn find(chars: &[char], from: usize, pat: &str) -> Option<usize> {
+    (from..chars.len()).find(|&j| starts(chars, j, pat))
+}
+
+fn collect(chars: &[char]) -> String {
+    chars.iter().collect()
+}
+
+// [text](url), with url absolute or relative to reddit.com
+fn link(chars: &[char], i: usize) -> Option<(String, usize)> {
+    let mid = find(chars, i + 1, "](")?;
+    let end = find(chars, mid + 2, ")")?;
+    let text = collect(&chars[i + 1..mid]);
+    let url = collect(&chars[mid + 2..end]);
+    let url = url.trim();
+    let url = if url.starts_with('/') {
+        format!("https://www.reddit.com{}", url)
+    } else if url.starts_with("https://") || url.starts_with("http://") {
+        url.to_string()
+    } else {
+        return None;
+    };
+    let html = format!("<a href=\"{}\">{}</a>", escape(&url), inline(&text));
+    Some((html, end + 1))
+}
+
+// Render emphasis, spoilers, inline code and links
+fn inline(s: &str) -> String {
+    // opening and closing delimiters, and the corresponding tag
+    let spans = [
+        (">!", "!<", "tg-spoiler"),
+        ("**", "**", "b"),
+        ("__", "__", "b"),
+        ("~~", "~~", "s"),
+        ("*", "*", "i"),
+        ("_", "_", "i"),
+    ];
+    let chars: Vec<char> = s.chars().collect();
+    let mut out = String::new();
+    let mut i = 0;
+    'outer: while i < chars.len() {
+        let c = chars[i];
+        if c == '\\' && chars.get(i + 1).is_some_and(|n| n.is_ascii_punctuation()) {
+            out.push_str(&escape(&chars[i + 1].to_string()));
+            i += 2;
+            continue;
+        }
+        if c == '`' {
+            if let Some(j) = find(&chars, i + 1, "`").filter(|&j| j > i + 1) {
+                out.push_str(&format!(
+                    "<code>{}</code>",
+                    escape(&collect(&chars[i + 1..j]))
+                ));
+                i = j + 1;
+                continue;
+            }
+        }
+        if c == '[' {
+            if let Some((html, next)) = link(&chars, i) {
+                out.push_str(&html);
+                i = next;
+                continue;
+            }
+        }
+        for (open, close, tag) in spans {
+            if !starts(&chars, i, open) {
+                continue;
+            }
+            let from = i + open.len();
+            // the content cannot start with a whitespace...
+            if chars.get(from).is_none_or(|n| n.is_whitespace()) {
+                continue;
+            }
+            // ...and underscores within words are not emphasis
+            if open == "_" && i > 0 && chars[i - 1].is_alphanumeric() {
+                continue;
+            }
+            if let Some(j) = find(&chars, from, close).filter(|&j| j > from) {
+                let content = inline(&collect(&chars[from..j]));
+                out.push_str(&format!("<{}>{}</{}>", tag, content, tag));
+                i = j + close.len();
+                continue 'outer;
+            }
+        }
+        out.push_str(&escape(&c.to_string()));
+        i += 1;
+    }
+    out
+}
+
+fn is_quote(t: &str) -> bool {
+    t.starts_with('>') && !t.starts_with(">!")
+}
+
+fn is_rule(t: &str) -> bool {
+    let t: Vec<char> = t.chars().filter(|c| !c.is_whitespace()).collect();
+    t.len() >= 3 && matches!(t[0], '-' | '*' | '_') && t.iter().all(|c| *c == t[0])
+}
+
+fn is_heading(t: &str) -> bool {
+    let level = t.chars().take_while(|c| *c == '#').count();
+    (1..=6).contains(&level)
+}
+
+fn list_item(t: &str) -> Option<String> {
+    for bullet in ["* ", "- ", "+ "] {
+        if let Some(item) = t.strip_prefix(bullet) {
+            return Some(format!("\u{2022} {}", item));
+        }
+    }
+    let num = t.chars().take_while(|c| c.is_ascii_digit()).count();
+    let is_num = num > 0 && t[num..].starts_with(". ");
+    is_num.then(|| t.to_string())
+}
+
+// Lines which interrupt a paragraph
+fn starts_block(line: &str) -> bool {
+    let t = line.trim();
+    t.is_empty()
+        || t.starts_with("```")
+        || t.starts_with('|')
+        || is_quote(t)
+        || is_heading(t)
+        || is_rule(t)
+}
+
+fn parse(md: &str) -> Vec<Block> {
+    let md = unescape(md);
+    let lines: Vec<&str> = md.lines().collect();
+    let indented = |l: &str| l.starts_with("    ") || l.starts_with('\t');
+    let mut blocks = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let t = lines[i].trim();
+        if t.is_empty() {
+            i += 1;
+        } else if t.starts_with("```") {
+            let mut code = Vec::new();
+            i += 1;
+            while i < lines.len() && !lines[i].trim().starts_with("```") {
+                code.push(lines[i]);
+                i += 1;
+            }
+            i += 1; // closing fence
+            blocks.push(Block::Code(code.join("\n")));
+        } else if indented(lines[i]) {
+            let mut code = Vec::new();
+            while i < lines.len() && (indented(lines[i]) || lines[i].trim().is_empty()) {
+                let l = lines[i].strip_prefix("    ");
+                code.push(
+                    l.or_else(|| lines[i].strip_prefix('\t'))
+                        .unwrap_or_default(),
+                );
+                i += 1;
+            }
+            blocks.push(Block::Code(code.join("\n").trim_end().to_string()));
+        } else if t.starts_with('|') {
+            // tables are shown as they are
+            let mut table = Vec::new();
+            while i < lines.len() && lines[i].trim().starts_with('|') {
+                table.push(lines[i].trim());
+                i += 1;
+            }
+            blocks.push(Block::Code(table.join("\n")));
+        } else if is_quote(t) {
+            // Telegram does not support nested quotes, flatten them
+            let mut quote = Vec::new();
+            while i < lines.len() && is_quote(lines[i].trim()) {
+                quote.push(lines[i].trim().trim_start_matches(['>', ' ']));
+                i += 1;
+            }
+            blocks.push(Block::Quote(quote.join("\n").trim().to_string()));
+        } else if is_heading(t) {
+            blocks.push(Block::Heading(t.trim_start_matches('#').trim().to_string()));
+            i += 1;
+        } else if is_rule(t) {
+            blocks.push(Block::Rule);
+            i += 1;
+        } else {
+            // paragraph or list: single newlines are spaces, unless ending with two spaces
+            let mut text = String::new();
+            let mut hard_break = false;
+            while i < lines.len() && (text.is_empty() || !starts_block(lines[i])) {
+                let t = lines[i].trim();
+                let item = list_item(t);
+                if !text.is_empty() {
+                    text.push(if hard_break || item.is_some() {
+                        '\n'
+                    } else {
+                        ' '
+                    });
+                }
+                text.push_str(&item.unwrap_or_else(|| t.to_string()));
+                hard_break = lines[i].ends_with("  ");
+                i += 1;
+            }
+            blocks.push(Block::Text(text));
+        }
+    }
+    blocks.retain(|b| matches!(b, Block::Rule) || !b.get_s().trim().is_empty());
+    blocks
+}
+
+// Split s in pieces of at most `limit` UTF-16 code units, preferably at whitespaces
+fn chunks(s: &str, limit: usize) -> Vec<String> {
+    let mut res = Vec::new();
+    let mut cur = String::new();
+    let mut cur_len = 0;
+    for word in s.split_inclusive(char::is_whitespace) {
+        let len: usize = word.chars().map(char::len_utf16).sum();
+        if cur_len + len > limit && !cur.is_empty() {
+            res.push(std::mem::take(&mut cur));
+            cur_len = 0;
+        }
+        for c in word.chars() {
+            if cur_len + c.len_utf16() > limit {
+                res.push(std::mem::take(&mut cur));
+                cur_len = 0;
+            }
+            cur.push(c);
+            cur_len += c.len_utf16();
+        }
+    }
+    if !cur.is_empty() {
+        res.push(cur);
+    }
+    res
+}
+
+// Render the markdown as HTML blocks, each one fitting in `limit` characters,
+// with their text hidden behind spoilers if asked
+pub fn to_html(md: &str, limit: usize, spoiler: bool) -> Vec<String> {
+    let mut res = Vec::new();
+    for block in parse(md) {
+        let html = block.render(spoiler);
+        if text_len(&html) <= limit {
+            res.push(html);
+        } else {
+            for s in chunks(block.get_s(), limit) {
+                res.push(block.with_s(s).render(spoiler));
+            }
+        }
+    }
+    res
+}
+
+// Join the blocks into as few messages of at most `limit` characters as possible
+pub fn paginate(blocks: &[String], limit: usize) -> Vec<String> {
+    let mut pages = Vec::new();
+    let mut cur = String::new();
+    for block in blocks {
+        if !cur.is_empty() && text_len(&cur) + 2 + text_len(block) > limit {
+            pages.push(std::mem::take(&mut cur));
+        }
+        if !cur.is_empty() {
+            cur.push_str("\n\n");
+        }
+        cur.push_str(block);
+    }
+    if !cur.is_empty() {
+        pages.push(cur);
+    }
+    pages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn html(md: &str) -> Vec<String> {
+        to_html(md, 4096, false)
+    }
+
+    #[test]
+    fn spoilers() {
+        assert_eq!(
+            html("the end >!he dies!< sadly"),
+            ["the end <tg-spoiler>he dies</tg-spoiler> sadly"]
+        );
+        // a quote is not a spoiler
+        assert_eq!(html(">!no end"), ["&gt;!no end"]);
+        assert_eq!(html("> quoted"), ["<blockquote>quoted</blockquote>"]);
+    }
+
+    #[test]
+    fn emphasis() {
+        assert_eq!(
+            html("**bold** *it* ~~del~~ __b__ _i_"),
+            ["<b>bold</b> <i>it</i> <s>del</s> <b>b</b> <i>i</i>"]
+        );
+        assert_eq!(html("**nested _it_**"), ["<b>nested <i>it</i></b>"]);
+        // no content, or starting with a whitespace
+        assert_eq!(html("2 * 3 * 4"), ["2 * 3 * 4"]);
+        assert_eq!(html("a **** b"), ["a **** b"]);
+    }
+
+    #[test]
+    fn underscores_in_words() {
+        assert_eq!(html("snake_case_name"), ["snake_case_name"]);
+        assert_eq!(html("a _word_ here"), ["a <i>word</i> here"]);
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(html(r"\*not it\*"), ["*not it*"]);
+        assert_eq!(html(r"a \< b"), ["a &lt; b"]);
+        assert_eq!(html("<b>&\"</b>"), ["&lt;b&gt;&amp;&quot;&lt;/b&gt;"]);
+        // entities stored by Reddit
+        assert_eq!(html("a &amp; b&nbsp;&lt;3&#x200B;"), ["a &amp; b &lt;3"]);
+    }
+
+    #[test]
+    fn inline_code() {
+        assert_eq!(
+            html("call `f(*a, *b)` now"),
+            ["call <code>f(*a, *b)</code> now"]
+        );
+        assert_eq!(html("empty `` ticks"), ["empty `` ticks"]);
+    }
+
+    #[test]
+    fn links() {
+        assert_eq!(
+            html("see [the docs](https://doc.rust-lang.org)"),
+            ["see <a href=\"https://doc.rust-lang.org\">the docs</a>"]
+        );
+        assert_eq!(
+            html("[**wiki**](/r/rust/wiki)"),
+            ["<a href=\"https://www.reddit.com/r/rust/wiki\"><b>wiki</b></a>"]
+        );
+        assert_eq!(
+            html("[x](https://a.b/?q=\"1\"&r=2)"),
+            ["<a href=\"https://a.b/?q=&quot;1&quot;&amp;r=2\">x</a>"]
+        );
+        // other schemes are not linked
+        assert_eq!(
+            html("[x](javascript:alert(1))"),
+            ["[x](javascript:alert(1))"]
+        );
+        assert_eq!(html("[x](ftp://a.b)"), ["[x](ftp://a.b)"]);
+        assert_eq!(html("[not a link]"), ["[not a link]"]);
+    }
+
+    #[test]
+    fn quotes() {
+        assert_eq!(
+            html("> first\n> second\n>> nested\n\nafter"),
+            ["<blockquote>first\nsecond\nnested</blockquote>", "after"]
+        );
+    }
+
+    #[test]
+    fn code_blocks() {
+        assert_eq!(
+            html("```\nfn main() {\n    <*>\n}\n```\ntext"),
+            ["<pre>fn main() {\n    &lt;*&gt;\n}</pre>", "text"]
+        );
+        assert_eq!(
+            html("text\n\n    let a = 1;\n\n    let b = 2;\n\nmore"),
+            ["text", "<pre>let a = 1;\n\nlet b = 2;</pre>", "more"]
+        );
+        // unclosed fences run to the end
+        assert_eq!(html("```\n**code**"), ["<pre>**code**</pre>"]);
+    }
+
+    #[test]
+    fn tables() {
+        assert_eq!(
+            html("| a | b |\n|---|---|\n| 1 | 2 |\nafter"),
+            ["<pre>| a | b |\n|---|---|\n| 1 | 2 |</pre>", "after"]
+        );
+    }
+
+    #[test]
+    fn paragraphs_and_lists() {
+        assert_eq!(
+            html("# Title\none\ntwo  \nthree\n\n* a\n- b\n1. c\n\n---"),
+            [
+                "<b>Title</b>",
+                "one two\nthree",
+                "\u{2022} a\n\u{2022} b\n1. c",
+                &"\u{2014}".repeat(10),
+            ]
+        );
+    }
+
+    #[test]
+    fn spoiled_blocks() {
+        let md = "# Head\ntext >!x!< `c`\n\n> quote\n\n```\ncode\n```";
+        assert_eq!(
+            to_html(md, 4096, true),
+            [
+                "<b><tg-spoiler>Head</tg-spoiler></b>",
+                "<tg-spoiler>text x c</tg-spoiler>",
+                "<blockquote><tg-spoiler>quote</tg-spoiler></blockquote>",
+                "<tg-spoiler>code</tg-spoiler>",
+            ]
+        );
+    }
+
+    #[test]
+    fn text_len_counts_shown_text() {
+        assert_eq!(text_len("<b>bold</b> &amp; <a href=\"x\">l</a>"), 8);
+        assert_eq!(text_len("&lt;&gt;"), 2);
+        // astral characters are two UTF-16 code units
+        assert_eq!(text_len("\u{1F600}é"), 3);
+        assert_eq!(text_len(""), 0);
+    }
+
+    #[test]
+    fn chunks_at_the_limit() {
+        assert_eq!(chunks("aaa bbb ccc", 8), ["aaa bbb ", "ccc"]);
+        assert_eq!(chunks("aaa bbb", 7), ["aaa bbb"]);
+        // words longer than the limit are cut
+        assert_eq!(chunks("abcdefgh", 3), ["abc", "def", "gh"]);
+        // astral characters are not split
+        assert_eq!(
+            chunks("\u{1F600}\u{1F600}\u{1F600}", 5),
+            ["\u{1F600}\u{1F600}", "\u{1F600}"]
+        );
+        assert!(chunks("", 5).is_empty());
+    }
+
+    #[test]
+    fn to_html_splits_long_blocks() {
+        let md = format!("{}\n\n```\n{}\n```", "word ".repeat(30), "x".repeat(25));
+        let blocks = to_html(&md, 20, false);
+        assert!(blocks.iter().all(|b| text_len(b) <= 20));
+        assert_eq!(blocks.len(), 8 + 2);
+        assert!(blocks[8].starts_with("<pre>") && blocks[9].starts_with("<pre>"));
+        assert_eq!(blocks.concat().matches("word").count(), 30);
+    }
+
+    #[test]
+    fn paginate_at_the_limit() {
+        let blocks = ["aaaa".to_string(), "bbbb".to_string(), "cc".to_string()];
+        assert_eq!(paginate(&blocks, 10), ["aaaa\n\nbbbb", "cc"]);
+        assert_eq!(paginate(&blocks, 9), ["aaaa", "bbbb\n\ncc"]);
+        assert!(paginate(&[], 10).is_empty());
+    }
+}
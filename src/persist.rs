@@ -1,16 +1,70 @@
+use crate::filters::Filters;
+use crate::reddit::{RedReq, RedditCmd};
+use crate::telegram::State;
+use crate::topic::{Dest, Topic};
 use crate::SubredditsCats;
 use sqlx::migrate::MigrateDatabase;
 use sqlx::Row;
 use sqlx::SqlitePool;
 use std::env;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use teloxide::dispatching::dialogue::Storage;
 use teloxide::prelude::ChatId;
 
+#[derive(Clone, Debug)]
+pub struct Subscription {
+    pub id: i64,
+    pub chat_id: ChatId,
+    // the chat itself (or one of its topics), or a channel
+    pub dest: Dest,
+    pub rcmd: RedditCmd,
+    pub schedule: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct Watch {
+    pub id: i64,
+    pub chat_id: ChatId,
+    pub pattern: String,
+    pub subreddit: String,
+    pub topic: Topic,
+    // publication time of the newest notified post (at first, creation time of the watch)
+    pub last_post_at: i64,
+}
+
+#[derive(Clone, Debug)]
+pub struct CachedMedia {
+    pub kind: String,
+    pub file_id: String,
+}
+
 pub async fn open_db() -> Result<SqlitePool, sqlx::Error> {
     let db_url = &env::var("DATABASE_URL").expect("Please set DATABASE_URL environment variable");
     sqlx::Sqlite::create_database(db_url)
         .await
         .expect("Cannot create DB");
     let db = SqlitePool::connect(db_url).await?;
+    create_tables(&db).await?;
+    Ok(db)
+}
+
+// A private DB, for the tests
+#[cfg(test)]
+pub async fn memory_db() -> SqlitePool {
+    // every connection would open another in-memory DB
+    let db = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .expect("Cannot open in-memory DB");
+    create_tables(&db).await.expect("Cannot create tables");
+    db
+}
+
+async fn create_tables(db: &SqlitePool) -> Result<(), sqlx::Error> {
     sqlx::query(
         "CREATE TABLE IF NOT EXISTS user_pref (
             user_id    INTEGER PRIMARY KEY,
@@ -18,9 +72,122 @@ pub async fn open_db() -> Result<SqlitePool, sqlx::Error> {
             subreddits TEXT NOT NULL
          )",
     )
-    .execute(&db)
+    .execute(db)
     .await?;
-    Ok(db)
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS subscriptions (
+            id         INTEGER PRIMARY KEY AUTOINCREMENT,
+            chat_id    INTEGER NOT NULL,
+            subreddit  TEXT NOT NULL,
+            category   TEXT NOT NULL,
+            view       TEXT NOT NULL,
+            tot        INTEGER NOT NULL,
+            schedule   TEXT NOT NULL,
+            next_run   INTEGER NOT NULL
+         )",
+    )
+    .execute(db)
+    .await?;
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS seen_posts (
+            chat_id    INTEGER NOT NULL,
+            key        TEXT NOT NULL,
+            seen_at    INTEGER NOT NULL,
+            PRIMARY KEY (chat_id, key)
+         )",
+    )
+    .execute(db)
+    .await?;
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS chat_settings (
+            chat_id    INTEGER NOT NULL,
+            key        TEXT NOT NULL,
+            value      TEXT NOT NULL,
+            PRIMARY KEY (chat_id, key)
+         )",
+    )
+    .execute(db)
+    .await?;
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS dialogues (
+            chat_id    INTEGER PRIMARY KEY,
+            state      TEXT NOT NULL
+         )",
+    )
+    .execute(db)
+    .await?;
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS watches (
+            id         INTEGER PRIMARY KEY AUTOINCREMENT,
+            chat_id    INTEGER NOT NULL,
+            pattern    TEXT NOT NULL,
+            subreddit  TEXT NOT NULL,
+            created_at INTEGER NOT NULL
+         )",
+    )
+    .execute(db)
+    .await?;
+    // sub_id is 0 for the filters applying to the whole chat
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS filters (
+            chat_id    INTEGER NOT NULL,
+            sub_id     INTEGER NOT NULL,
+            rules      TEXT NOT NULL,
+            PRIMARY KEY (chat_id, sub_id)
+         )",
+    )
+    .execute(db)
+    .await?;
+    // where to deliver, when not to the chat itself
+    add_column(db, "subscriptions", "dest_id", "INTEGER").await?;
+    add_column(db, "subscriptions", "topic", "INTEGER").await?;
+    add_column(db, "watches", "topic", "INTEGER").await?;
+    add_column(db, "watches", "last_post_at", "INTEGER").await?;
+    // actions of the buttons whose callback data would be too long
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS callbacks (
+            key        TEXT PRIMARY KEY,
+            action     TEXT NOT NULL,
+            created_at INTEGER NOT NULL
+         )",
+    )
+    .execute(db)
+    .await?;
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS media_cache (
+            key        TEXT PRIMARY KEY,
+            kind       TEXT NOT NULL,
+            file_id    TEXT NOT NULL,
+            cached_at  INTEGER NOT NULL
+         )",
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+// Add a column missing from a table created by an older version
+async fn add_column(
+    db: &SqlitePool,
+    table: &str,
+    column: &str,
+    def: &str,
+) -> Result<(), sqlx::Error> {
+    let found: i64 =
+        sqlx::query_scalar("SELECT COUNT(*) FROM pragma_table_info(?) WHERE name = ?;")
+            .bind(table)
+            .bind(column)
+            .fetch_one(db)
+            .await?;
+    if found == 0 {
+        sqlx::query(&format!(
+            "ALTER TABLE {} ADD COLUMN {} {}",
+            table, column, def
+        ))
+        .execute(db)
+        .await?;
+    }
+    Ok(())
 }
 
 pub async fn insert_pref(
@@ -94,3 +261,483 @@ pub async fn fetch_subs(
     };
     Ok(res)
 }
+
+fn row_to_subscription(r: &sqlx::sqlite::SqliteRow) -> Subscription {
+    let view: String = r.get("view");
+    let rcmd = RedditCmd {
+        view: RedReq::from_str(&view).unwrap_or(RedReq::Hot),
+        subreddit: r.get("subreddit"),
+        tot: r.get("tot"),
+        category: r.get("category"),
+        last_seen: None,
+        search: None,
+    };
+    let chat_id = ChatId(r.get("chat_id"));
+    let dest_id: Option<i64> = r.get("dest_id");
+    Subscription {
+        id: r.get("id"),
+        chat_id,
+        dest: Dest::new(dest_id.map_or(chat_id, ChatId), r.get("topic")),
+        rcmd,
+        schedule: r.get("schedule"),
+    }
+}
+
+pub async fn insert_subscription(
+    db: &SqlitePool,
+    chat_id: ChatId,
+    dest: Dest,
+    rcmd: &RedditCmd,
+    schedule: &str,
+    next_run: i64,
+) -> Result<i64, sqlx::Error> {
+    let mut conn = db.acquire().await?;
+    let res = sqlx::query(
+        "INSERT INTO subscriptions
+         (chat_id, subreddit, category, view, tot, schedule, next_run, dest_id, topic)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+    )
+    .bind(chat_id.0)
+    .bind(&rcmd.subreddit)
+    .bind(&rcmd.category)
+    .bind(rcmd.view.to_string())
+    .bind(rcmd.tot)
+    .bind(schedule)
+    .bind(next_run)
+    .bind((dest.chat_id != chat_id).then_some(dest.chat_id.0))
+    .bind(dest.topic)
+    .execute(&mut conn)
+    .await?;
+    Ok(res.last_insert_rowid())
+}
+
+pub async fn fetch_subscriptions(
+    db: &SqlitePool,
+    chat_id: ChatId,
+) -> Result<Vec<Subscription>, sqlx::Error> {
+    let res = sqlx::query("SELECT * FROM subscriptions WHERE chat_id = ? ORDER BY id;")
+        .bind(chat_id.0)
+        .fetch_all(db)
+        .await?;
+    Ok(res.iter().map(row_to_subscription).collect())
+}
+
+pub async fn fetch_due_subscriptions(
+    db: &SqlitePool,
+    now: i64,
+) -> Result<Vec<Subscription>, sqlx::Error> {
+    let res = sqlx::query("SELECT * FROM subscriptions WHERE next_run <= ? ORDER BY next_run;")
+        .bind(now)
+        .fetch_all(db)
+        .await?;
+    Ok(res.iter().map(row_to_subscription).collect())
+}
+
+pub async fn set_next_run(db: &SqlitePool, id: i64, next_run: i64) -> Result<(), sqlx::Error> {
+    let mut conn = db.acquire().await?;
+    sqlx::query("UPDATE subscriptions SET next_run = ? WHERE id = ?;")
+        .bind(next_run)
+        .bind(id)
+        .execute(&mut conn)
+        .await?;
+    Ok(())
+}
+
+pub async fn del_subscription(
+    db: &SqlitePool,
+    chat_id: ChatId,
+    id: i64,
+) -> Result<u64, sqlx::Error> {
+    let mut conn = db.acquire().await?;
+    let res = sqlx::query("DELETE FROM subscriptions WHERE chat_id = ? AND id = ?;")
+        .bind(chat_id.0)
+        .bind(id)
+        .execute(&mut conn)
+        .await?;
+    sqlx::query("DELETE FROM filters WHERE chat_id = ? AND sub_id = ?;")
+        .bind(chat_id.0)
+        .bind(id)
+        .execute(&mut conn)
+        .await?;
+    Ok(res.rows_affected())
+}
+
+// Filters of the chat, or of one of its subscriptions
+pub async fn get_filters(
+    db: &SqlitePool,
+    chat_id: ChatId,
+    sub_id: Option<i64>,
+) -> Result<Filters, sqlx::Error> {
+    let res = sqlx::query("SELECT rules FROM filters WHERE chat_id = ? AND sub_id = ?;")
+        .bind(chat_id.0)
+        .bind(sub_id.unwrap_or(0))
+        .fetch_optional(db)
+        .await?;
+    let res = match res {
+        Some(r) => {
+            let r: String = r.get(0);
+            serde_json::from_str(&r)
+                .unwrap_or_else(|_| panic!("Error while parsing DB filters: {}", r))
+        }
+        None => Filters::default(),
+    };
+    Ok(res)
+}
+
+pub async fn set_filters(
+    db: &SqlitePool,
+    chat_id: ChatId,
+    sub_id: Option<i64>,
+    filters: &Filters,
+) -> Result<(), sqlx::Error> {
+    let mut conn = db.acquire().await?;
+    if filters.is_empty() {
+        sqlx::query("DELETE FROM filters WHERE chat_id = ? AND sub_id = ?;")
+            .bind(chat_id.0)
+            .bind(sub_id.unwrap_or(0))
+            .execute(&mut conn)
+            .await?;
+        return Ok(());
+    }
+    let rules = serde_json::to_string(filters).unwrap();
+    sqlx::query(
+        "INSERT INTO filters (chat_id, sub_id, rules) VALUES (?1, ?2, ?3)
+         ON CONFLICT DO UPDATE SET rules=excluded.rules",
+    )
+    .bind(chat_id.0)
+    .bind(sub_id.unwrap_or(0))
+    .bind(rules)
+    .execute(&mut conn)
+    .await?;
+    Ok(())
+}
+
+fn row_to_watch(r: &sqlx::sqlite::SqliteRow) -> Watch {
+    let created_at: i64 = r.get("created_at");
+    Watch {
+        id: r.get("id"),
+        chat_id: ChatId(r.get("chat_id")),
+        pattern: r.get("pattern"),
+        subreddit: r.get("subreddit"),
+        topic: r.get("topic"),
+        last_post_at: r
+            .get::<Option<i64>, _>("last_post_at")
+            .unwrap_or(created_at),
+    }
+}
+
+pub async fn insert_watch(
+    db: &SqlitePool,
+    chat_id: ChatId,
+    topic: Topic,
+    pattern: &str,
+    subreddit: &str,
+    now: i64,
+) -> Result<i64, sqlx::Error> {
+    let mut conn = db.acquire().await?;
+    let res = sqlx::query(
+        "INSERT INTO watches (chat_id, pattern, subreddit, created_at, topic)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+    )
+    .bind(chat_id.0)
+    .bind(pattern)
+    .bind(subreddit)
+    .bind(now)
+    .bind(topic)
+    .execute(&mut conn)
+    .await?;
+    Ok(res.last_insert_rowid())
+}
+
+// Watches of a chat, or of all the chats
+pub async fn fetch_watches(
+    db: &SqlitePool,
+    chat_id: Option<ChatId>,
+) -> Result<Vec<Watch>, sqlx::Error> {
+    let res = match chat_id {
+        Some(chat_id) => {
+            sqlx::query("SELECT * FROM watches WHERE chat_id = ? ORDER BY id;")
+                .bind(chat_id.0)
+                .fetch_all(db)
+                .await?
+        }
+        None => {
+            sqlx::query("SELECT * FROM watches ORDER BY id;")
+                .fetch_all(db)
+                .await?
+        }
+    };
+    Ok(res.iter().map(row_to_watch).collect())
+}
+
+pub async fn set_watch_mark(
+    db: &SqlitePool,
+    id: i64,
+    last_post_at: i64,
+) -> Result<(), sqlx::Error> {
+    let mut conn = db.acquire().await?;
+    sqlx::query(
+        "UPDATE watches SET last_post_at = ? WHERE id = ? AND IFNULL(last_post_at, 0) < ?;",
+    )
+    .bind(last_post_at)
+    .bind(id)
+    .bind(last_post_at)
+    .execute(&mut conn)
+    .await?;
+    Ok(())
+}
+
+pub async fn del_watch(db: &SqlitePool, chat_id: ChatId, id: i64) -> Result<u64, sqlx::Error> {
+    let mut conn = db.acquire().await?;
+    let res = sqlx::query("DELETE FROM watches WHERE chat_id = ? AND id = ?;")
+        .bind(chat_id.0)
+        .bind(id)
+        .execute(&mut conn)
+        .await?;
+    Ok(res.rows_affected())
+}
+
+pub async fn is_seen(
+    db: &SqlitePool,
+    chat_id: ChatId,
+    keys: &[String],
+) -> Result<bool, sqlx::Error> {
+    if keys.is_empty() {
+        return Ok(false);
+    }
+    let placeholders = vec!["?"; keys.len()].join(", ");
+    let sql = format!(
+        "SELECT 1 FROM seen_posts WHERE chat_id = ? AND key IN ({}) LIMIT 1;",
+        placeholders
+    );
+    let mut query = sqlx::query(&sql).bind(chat_id.0);
+    for key in keys {
+        query = query.bind(key);
+    }
+    Ok(query.fetch_optional(db).await?.is_some())
+}
+
+pub async fn mark_seen(
+    db: &SqlitePool,
+    chat_id: ChatId,
+    keys: &[String],
+    now: i64,
+) -> Result<(), sqlx::Error> {
+    let mut conn = db.acquire().await?;
+    for key in keys {
+        sqlx::query(
+            "INSERT INTO seen_posts (chat_id, key, seen_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT DO UPDATE SET seen_at=excluded.seen_at",
+        )
+        .bind(chat_id.0)
+        .bind(key)
+        .bind(now)
+        .execute(&mut conn)
+        .await?;
+    }
+    Ok(())
+}
+
+pub async fn prune_seen(db: &SqlitePool, older_than: i64) -> Result<u64, sqlx::Error> {
+    let mut conn = db.acquire().await?;
+    let res = sqlx::query("DELETE FROM seen_posts WHERE seen_at < ?;")
+        .bind(older_than)
+        .execute(&mut conn)
+        .await?;
+    Ok(res.rows_affected())
+}
+
+pub async fn get_setting(
+    db: &SqlitePool,
+    chat_id: ChatId,
+    key: &str,
+) -> Result<Option<String>, sqlx::Error> {
+    let res = sqlx::query("SELECT value FROM chat_settings WHERE chat_id = ? AND key = ?;")
+        .bind(chat_id.0)
+        .bind(key)
+        .fetch_optional(db)
+        .await?;
+    Ok(res.map(|r| r.get(0)))
+}
+
+pub async fn set_setting(
+    db: &SqlitePool,
+    chat_id: ChatId,
+    key: &str,
+    value: &str,
+) -> Result<(), sqlx::Error> {
+    let mut conn = db.acquire().await?;
+    sqlx::query(
+        "INSERT INTO chat_settings (chat_id, key, value) VALUES (?1, ?2, ?3)
+         ON CONFLICT DO UPDATE SET value=excluded.value",
+    )
+    .bind(chat_id.0)
+    .bind(key)
+    .bind(value)
+    .execute(&mut conn)
+    .await?;
+    Ok(())
+}
+
+pub async fn del_setting(db: &SqlitePool, chat_id: ChatId, key: &str) -> Result<u64, sqlx::Error> {
+    let mut conn = db.acquire().await?;
+    let res = sqlx::query("DELETE FROM chat_settings WHERE chat_id = ? AND key = ?;")
+        .bind(chat_id.0)
+        .bind(key)
+        .execute(&mut conn)
+        .await?;
+    Ok(res.rows_affected())
+}
+
+pub async fn get_cached_media(
+    db: &SqlitePool,
+    key: &str,
+    newer_than: i64,
+) -> Result<Option<CachedMedia>, sqlx::Error> {
+    let res =
+        sqlx::query("SELECT kind, file_id FROM media_cache WHERE key = ? AND cached_at >= ?;")
+            .bind(key)
+            .bind(newer_than)
+            .fetch_optional(db)
+            .await?;
+    Ok(res.map(|r| CachedMedia {
+        kind: r.get("kind"),
+        file_id: r.get("file_id"),
+    }))
+}
+
+pub async fn cache_media(
+    db: &SqlitePool,
+    keys: &[String],
+    media: &CachedMedia,
+    now: i64,
+) -> Result<(), sqlx::Error> {
+    let mut conn = db.acquire().await?;
+    for key in keys {
+        sqlx::query(
+            "INSERT INTO media_cache (key, kind, file_id, cached_at) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT DO UPDATE SET
+             kind=excluded.kind, file_id=excluded.file_id, cached_at=excluded.cached_at",
+        )
+        .bind(key)
+        .bind(&media.kind)
+        .bind(&media.file_id)
+        .bind(now)
+        .execute(&mut conn)
+        .await?;
+    }
+    Ok(())
+}
+
+pub async fn forget_media(db: &SqlitePool, file_id: &str) -> Result<u64, sqlx::Error> {
+    let mut conn = db.acquire().await?;
+    let res = sqlx::query("DELETE FROM media_cache WHERE file_id = ?;")
+        .bind(file_id)
+        .execute(&mut conn)
+        .await?;
+    Ok(res.rows_affected())
+}
+
+pub async fn prune_media_cache(db: &SqlitePool, older_than: i64) -> Result<u64, sqlx::Error> {
+    let mut conn = db.acquire().await?;
+    let res = sqlx::query("DELETE FROM media_cache WHERE cached_at < ?;")
+        .bind(older_than)
+        .execute(&mut conn)
+        .await?;
+    Ok(res.rows_affected())
+}
+
+pub async fn insert_callback(
+    db: &SqlitePool,
+    key: &str,
+    action: &str,
+    now: i64,
+) -> Result<(), sqlx::Error> {
+    let mut conn = db.acquire().await?;
+    sqlx::query(
+        "INSERT INTO callbacks (key, action, created_at) VALUES (?1, ?2, ?3)
+         ON CONFLICT DO UPDATE SET created_at=excluded.created_at",
+    )
+    .bind(key)
+    .bind(action)
+    .bind(now)
+    .execute(&mut conn)
+    .await?;
+    Ok(())
+}
+
+pub async fn get_callback(db: &SqlitePool, key: &str) -> Result<Option<String>, sqlx::Error> {
+    let res = sqlx::query("SELECT action FROM callbacks WHERE key = ?;")
+        .bind(key)
+        .fetch_optional(db)
+        .await?;
+    Ok(res.map(|r| r.get(0)))
+}
+
+pub async fn prune_callbacks(db: &SqlitePool, older_than: i64) -> Result<u64, sqlx::Error> {
+    let mut conn = db.acquire().await?;
+    let res = sqlx::query("DELETE FROM callbacks WHERE created_at < ?;")
+        .bind(older_than)
+        .execute(&mut conn)
+        .await?;
+    Ok(res.rows_affected())
+}
+
+// Dialogue states, saved in the DB so that menus survive restarts
+pub struct DialogueStorage {
+    db: SqlitePool,
+}
+
+impl DialogueStorage {
+    pub fn new(db: SqlitePool) -> Arc<Self> {
+        Arc::new(DialogueStorage { db })
+    }
+}
+
+type StorageFuture<T> = Pin<Box<dyn Future<Output = Result<T, sqlx::Error>> + Send>>;
+
+impl Storage<State> for DialogueStorage {
+    type Error = sqlx::Error;
+
+    fn remove_dialogue(self: Arc<Self>, chat_id: ChatId) -> StorageFuture<()> {
+        Box::pin(async move {
+            sqlx::query("DELETE FROM dialogues WHERE chat_id = ?;")
+                .bind(chat_id.0)
+                .execute(&self.db)
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn update_dialogue(self: Arc<Self>, chat_id: ChatId, dialogue: State) -> StorageFuture<()> {
+        Box::pin(async move {
+            let state = serde_json::to_string(&dialogue).unwrap();
+            sqlx::query(
+                "INSERT INTO dialogues (chat_id, state) VALUES (?1, ?2)
+                 ON CONFLICT DO UPDATE SET state=excluded.state",
+            )
+            .bind(chat_id.0)
+            .bind(state)
+            .execute(&self.db)
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn get_dialogue(self: Arc<Self>, chat_id: ChatId) -> StorageFuture<Option<State>> {
+        Box::pin(async move {
+            let res = sqlx::query("SELECT state FROM dialogues WHERE chat_id = ?;")
+                .bind(chat_id.0)
+                .fetch_optional(&self.db)
+                .await?;
+            let state = res.and_then(|r| {
+                let r: String = r.get(0);
+                // states saved by older versions are dropped, restarting the menu
+                serde_json::from_str(&r)
+                    .map_err(|e| log::info!("Cannot restore dialogue of {}: {}", chat_id, e))
+                    .ok()
+            });
+            Ok(state)
+        })
+    }
+}
@@ -1,233 +1,1802 @@
-use crate::UrlMatches;
+use crate::cache;
+use crate::callback::{self, Action};
+use crate::caption;
+use crate::dash;
+use crate::filters::Filter;
+use crate::markdown;
+use crate::persist;
+use crate::scheduler;
+use crate::topic::{Dest, InTopic};
+use crate::transcode;
+use crate::{MediaCache, MediaPipeline, MyState, NsfwPolicy, UrlMatches};
 use anyhow::Result;
-use roux::util::{FeedOption, TimePeriod};
-use roux::{response::BasicThing, submission::SubmissionData, Subreddit};
+use reqwest::header::{CONTENT_TYPE, RANGE};
+use reqwest::{redirect, StatusCode};
+use roux::comment::CommentData;
+use roux::response::{BasicListing, BasicThing};
+use roux::submission::SubmissionData;
+use roux::util::FeedOption;
+use roux::MaybeReplies;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
 use std::fs;
+use std::io::Read;
+use std::mem;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Duration;
+use strum::IntoEnumIterator;
 use strum_macros::{Display, EnumIter, EnumString};
-use teloxide::payloads::{SendDocumentSetters, SendPhotoSetters, SendVideoSetters};
+use teloxide::payloads::EditMessageReplyMarkupSetters;
+use teloxide::payloads::{
+    SendAnimationSetters, SendAudioSetters, SendDocumentSetters, SendMessageSetters,
+    SendPhotoSetters, SendVideoSetters,
+};
 use teloxide::prelude::{ChatId, Requester};
-use teloxide::types::{InputFile, Message};
+use teloxide::types::{
+    InlineKeyboardButton, InlineKeyboardMarkup, InlineQueryResult, InlineQueryResultArticle,
+    InlineQueryResultPhoto, InputFile, InputMedia, InputMediaAudio, InputMediaDocument,
+    InputMediaPhoto, InputMediaVideo, InputMessageContent, InputMessageContentText, Message,
+    ParseMode,
+};
 use teloxide::Bot;
+use tokio::io::AsyncWriteExt;
 use tokio::process::Command;
 use url::Url;
 use uuid::Uuid;
 
-#[derive(Display, Debug, Clone, EnumIter, EnumString)]
+// Time periods of the Top and Controversial views: Hour, Day, ..., All time
+#[derive(Display, Debug, Clone, Copy, PartialEq, EnumIter, EnumString, Serialize, Deserialize)]
+pub enum Period {
+    H,
+    D,
+    W,
+    M,
+    Y,
+    A,
+}
+
+impl Period {
+    pub fn from_query(s: &str) -> Option<Period> {
+        Period::iter().find(|p| p.as_query() == s)
+    }
+    // "w", "week" or "W"; "today" is also accepted
+    pub fn from_alias(s: &str) -> Option<Period> {
+        let s = s.to_lowercase();
+        match s.as_str() {
+            "today" => Some(Period::D),
+            _ => Period::from_query(&s).or_else(|| Period::from_str(&s.to_uppercase()).ok()),
+        }
+    }
+    pub fn as_query(&self) -> &'static str {
+        match self {
+            Period::H => "hour",
+            Period::D => "day",
+            Period::W => "week",
+            Period::M => "month",
+            Period::Y => "year",
+            Period::A => "all",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum RedReq {
+    Best,
+    Hot,
+    New,
     Rise,
+    Top(Period),
+    Controversial(Period),
+}
+
+// Shown as, e.g., "Hot", "TopD" and "ContW"
+impl fmt::Display for RedReq {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RedReq::Best => write!(f, "Best"),
+            RedReq::Hot => write!(f, "Hot"),
+            RedReq::New => write!(f, "New"),
+            RedReq::Rise => write!(f, "Rise"),
+            RedReq::Top(period) => write!(f, "Top{}", period),
+            RedReq::Controversial(period) => write!(f, "Cont{}", period),
+        }
+    }
+}
+
+impl FromStr for RedReq {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("Invalid view: {}", s);
+        let period = |p: &str| Period::from_str(p).map_err(|_| err());
+        match s {
+            "Best" => Ok(RedReq::Best),
+            "Hot" => Ok(RedReq::Hot),
+            "New" => Ok(RedReq::New),
+            "Rise" => Ok(RedReq::Rise),
+            _ => match (s.strip_prefix("Top"), s.strip_prefix("Cont")) {
+                (Some(p), _) => Ok(RedReq::Top(period(p)?)),
+                (_, Some(p)) => Ok(RedReq::Controversial(period(p)?)),
+                _ => Err(err()),
+            },
+        }
+    }
+}
+
+impl RedReq {
+    // All the views, grouped by kind
+    pub fn groups() -> Vec<Vec<RedReq>> {
+        vec![
+            vec![RedReq::Best, RedReq::Hot, RedReq::New, RedReq::Rise],
+            Period::iter().map(RedReq::Top).collect(),
+            Period::iter().map(RedReq::Controversial).collect(),
+        ]
+    }
+    // "TopW" as shown, or a lowercase name like "rising" (Top and Cont default to a day)
+    pub fn from_alias(s: &str) -> Option<RedReq> {
+        if let Ok(view) = RedReq::from_str(s) {
+            return Some(view);
+        }
+        match s.to_lowercase().as_str() {
+            "best" => Some(RedReq::Best),
+            "hot" => Some(RedReq::Hot),
+            "new" => Some(RedReq::New),
+            "rise" | "rising" => Some(RedReq::Rise),
+            "top" => Some(RedReq::Top(Period::D)),
+            "cont" | "controversial" => Some(RedReq::Controversial(Period::D)),
+            _ => None,
+        }
+    }
+    // The views available for the submissions of a user
+    pub fn user_groups() -> Vec<Vec<RedReq>> {
+        let mut groups = RedReq::groups();
+        groups[0] = vec![RedReq::Hot, RedReq::New];
+        groups
+    }
+}
+
+#[derive(Display, Debug, Clone, Copy, PartialEq, EnumIter, EnumString, Serialize, Deserialize)]
+#[strum(serialize_all = "lowercase")]
+pub enum SearchSort {
+    Relevance,
     Hot,
-    TopD,
-    TopW,
-    TopM,
-    TopY,
-    TopA,
+    Top,
+    New,
+    Comments,
+}
+
+impl SearchSort {
+    pub fn as_query(&self) -> &'static str {
+        match self {
+            SearchSort::Relevance => "relevance",
+            SearchSort::Hot => "hot",
+            SearchSort::Top => "top",
+            SearchSort::New => "new",
+            SearchSort::Comments => "comments",
+        }
+    }
+    pub fn has_period(&self) -> bool {
+        !matches!(self, SearchSort::Hot | SearchSort::New)
+    }
 }
 
-#[derive(Clone, Debug)]
+// A search, with the options still to be chosen set to None
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Search {
+    pub query: String,
+    pub sort: Option<SearchSort>,
+    pub period: Option<Period>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct RedditCmd {
     pub view: RedReq,
     pub subreddit: String,
     pub tot: u32,
     pub category: String,
     pub last_seen: Option<String>,
+    pub search: Option<Search>,
+}
+
+// Submission data, plus the fields skipped by roux
+#[derive(Debug, Deserialize)]
+pub struct PostData {
+    #[serde(flatten)]
+    pub base: SubmissionData,
+    pub media_metadata: Option<HashMap<String, MediaMeta>>,
+    pub gallery_data: Option<GalleryData>,
+    pub secure_media: Option<SecureMedia>,
+    pub crosspost_parent_list: Option<Vec<CrosspostParent>>,
+    // fullname of the crossposted post
+    pub crosspost_parent: Option<String>,
+    #[serde(default)]
+    pub spoiler: bool,
+    pub preview: Option<Preview>,
+}
+
+impl PostData {
+    // NSFW or spoiler post
+    pub fn is_sensitive(&self) -> bool {
+        self.base.over_18 || self.spoiler
+    }
+    // The smallest preview at least 320 pixels wide
+    fn thumbnail(&self) -> Option<&PreviewSource> {
+        let image = self.preview.as_ref()?.images.first()?;
+        image
+            .resolutions
+            .iter()
+            .find(|r| r.width >= 320)
+            .or(Some(&image.source))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CrosspostParent {
+    pub secure_media: Option<SecureMedia>,
+    pub media_metadata: Option<HashMap<String, MediaMeta>>,
+    pub gallery_data: Option<GalleryData>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SecureMedia {
+    pub reddit_video: Option<RedditVideo>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RedditVideo {
+    pub dash_url: Option<String>,
+    pub fallback_url: Option<String>,
+    pub duration: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GalleryData {
+    pub items: Vec<GalleryItem>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GalleryItem {
+    pub media_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MediaMeta {
+    pub status: Option<String>,
+    // "Image" or "AnimatedImage"
+    pub e: Option<String>,
+    // mime type
+    pub m: Option<String>,
+    pub s: Option<MediaSource>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MediaSource {
+    pub u: Option<String>,
+    pub gif: Option<String>,
+    pub mp4: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Preview {
+    pub images: Vec<PreviewImage>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PreviewImage {
+    pub source: PreviewSource,
+    // smallest first
+    #[serde(default)]
+    pub resolutions: Vec<PreviewSource>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PreviewSource {
+    pub url: String,
+    pub width: i32,
+    pub height: i32,
+}
+
+pub type Post = BasicThing<PostData>;
+
+// A comment, flattened from the thread
+#[derive(Debug)]
+struct Comment {
+    depth: usize,
+    author: String,
+    score: i32,
+    body: String,
 }
 
 #[derive(Debug)]
 enum FSFile {
     Image { f: String },
+    Animation { f: String },
     Video { f: String },
+    Audio { f: String },
+    Document { f: String },
 }
 
 impl FSFile {
     fn get_f(&self) -> String {
         match self {
             FSFile::Image { f } => f.to_string(),
+            FSFile::Animation { f } => f.to_string(),
             FSFile::Video { f } => f.to_string(),
+            FSFile::Audio { f } => f.to_string(),
+            FSFile::Document { f } => f.to_string(),
+        }
+    }
+    // kind of file reported by cache::sent_media
+    fn from_kind(kind: &str) -> Option<FSFile> {
+        let f = "".to_string();
+        let file = match kind {
+            "photo" => FSFile::Image { f },
+            "animation" => FSFile::Animation { f },
+            "video" => FSFile::Video { f },
+            "audio" => FSFile::Audio { f },
+            "document" => FSFile::Document { f },
+            _ => return None,
+        };
+        Some(file)
+    }
+    // same kind of file, stored in f
+    fn with_f(&self, f: String) -> FSFile {
+        match self {
+            FSFile::Image { f: _ } => FSFile::Image { f },
+            FSFile::Animation { f: _ } => FSFile::Animation { f },
+            FSFile::Video { f: _ } => FSFile::Video { f },
+            FSFile::Audio { f: _ } => FSFile::Audio { f },
+            FSFile::Document { f: _ } => FSFile::Document { f },
         }
     }
     async fn send_out(
         &self,
         bot: &Bot,
-        chat_id: ChatId,
+        dest: Dest,
         fname: InputFile,
         tit: &String,
+        blur: bool,
     ) -> Result<Message, teloxide::RequestError> {
         match self {
             FSFile::Image { f: _ } => {
                 // send as image
-                let res = bot.send_photo(chat_id, fname.clone()).caption(tit).await;
+                let res = bot
+                    .send_photo(dest.chat_id, fname.clone())
+                    .in_topic(dest.topic)
+                    .caption(tit)
+                    .parse_mode(ParseMode::Html)
+                    .has_spoiler(blur)
+                    .await;
                 if res.is_ok() {
                     res
                 }
                 // if resolution is too high, send as document
                 else {
-                    bot.send_document(chat_id, fname).caption(tit).await
+                    bot.send_document(dest.chat_id, fname)
+                        .in_topic(dest.topic)
+                        .caption(tit)
+                        .parse_mode(ParseMode::Html)
+                        .await
                 }
             }
-            FSFile::Video { f: _ } => bot.send_video(chat_id, fname).caption(tit).await,
+            FSFile::Animation { f: _ } => {
+                bot.send_animation(dest.chat_id, fname)
+                    .in_topic(dest.topic)
+                    .caption(tit)
+                    .parse_mode(ParseMode::Html)
+                    .has_spoiler(blur)
+                    .await
+            }
+            FSFile::Video { f: _ } => {
+                bot.send_video(dest.chat_id, fname)
+                    .in_topic(dest.topic)
+                    .caption(tit)
+                    .parse_mode(ParseMode::Html)
+                    .has_spoiler(blur)
+                    .await
+            }
+            FSFile::Audio { f: _ } => {
+                bot.send_audio(dest.chat_id, fname)
+                    .in_topic(dest.topic)
+                    .caption(tit)
+                    .parse_mode(ParseMode::Html)
+                    .await
+            }
+            FSFile::Document { f: _ } => {
+                bot.send_document(dest.chat_id, fname)
+                    .in_topic(dest.topic)
+                    .caption(tit)
+                    .parse_mode(ParseMode::Html)
+                    .await
+            }
+        }
+    }
+    // media groups accept only photos and videos (or only audios, or only documents)
+    fn to_media(&self, fname: InputFile, tit: Option<String>, blur: bool) -> InputMedia {
+        let tit = tit.unwrap_or_default();
+        match self {
+            FSFile::Image { f: _ } => InputMedia::Photo(InputMediaPhoto {
+                has_spoiler: blur,
+                ..InputMediaPhoto::new(fname)
+                    .caption(tit)
+                    .parse_mode(ParseMode::Html)
+            }),
+            FSFile::Animation { f: _ } | FSFile::Video { f: _ } => {
+                InputMedia::Video(InputMediaVideo {
+                    has_spoiler: blur,
+                    ..InputMediaVideo::new(fname)
+                        .caption(tit)
+                        .parse_mode(ParseMode::Html)
+                })
+            }
+            FSFile::Audio { f: _ } => InputMedia::Audio(
+                InputMediaAudio::new(fname)
+                    .caption(tit)
+                    .parse_mode(ParseMode::Html),
+            ),
+            FSFile::Document { f: _ } => InputMedia::Document(
+                InputMediaDocument::new(fname)
+                    .caption(tit)
+                    .parse_mode(ParseMode::Html),
+            ),
         }
     }
 }
 
-async fn get_posts_raw(rcmd: &mut RedditCmd) -> Vec<BasicThing<SubmissionData>> {
-    let mut subreddit = rcmd.subreddit.clone();
-    subreddit.retain(|c| !c.is_whitespace()); // remove whitespaces
-    let subreddit = Subreddit::new(&subreddit);
-    let tot = rcmd.tot;
-    let mut fopts = FeedOption::new().limit(tot);
+fn http_client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        reqwest::Client::builder()
+            .user_agent(concat!("reddit_fetcher/", env!("CARGO_PKG_VERSION")))
+            .connect_timeout(Duration::from_secs(10))
+            .timeout(Duration::from_secs(120))
+            .redirect(redirect::Policy::limited(5))
+            .build()
+            .expect("Cannot build HTTP client")
+    })
+}
+
+#[derive(Debug)]
+pub enum RedditError {
+    NotFound,
+    // suspended and deleted accounts are also reported as missing or forbidden
+    UserNotFound,
+    Private,
+    Banned,
+    RateLimited,
+    Network(String),
+    Parse(String),
+}
+
+impl fmt::Display for RedditError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RedditError::NotFound => write!(f, "There is no such subreddit (or user)."),
+            RedditError::UserNotFound => write!(
+                f,
+                "There is no such user, or their account has been suspended or deleted."
+            ),
+            RedditError::Private => write!(f, "This community is private or quarantined."),
+            RedditError::Banned => write!(f, "This community has been banned from Reddit."),
+            RedditError::RateLimited => write!(
+                f,
+                "Reddit is limiting the requests of the bot, please try again in a few minutes."
+            ),
+            RedditError::Network(e) => write!(f, "Cannot reach Reddit: {}", e),
+            RedditError::Parse(e) => write!(f, "Unexpected answer from Reddit: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RedditError {}
+
+// The error reported by an HTTP status (and reason), for a user's feed or a subreddit
+fn error_of(status: StatusCode, reason: Option<&str>, user: bool) -> RedditError {
+    match (status.as_u16(), reason) {
+        (429, _) => RedditError::RateLimited,
+        (403 | 404, _) if user => RedditError::UserNotFound,
+        (_, Some("banned")) => RedditError::Banned,
+        (_, Some("private" | "quarantined" | "gold_only")) | (403, _) => RedditError::Private,
+        (404, _) => RedditError::NotFound,
+        _ => RedditError::Network(status.to_string()),
+    }
+}
+
+// Body of Reddit error responses
+#[derive(Debug, Deserialize)]
+struct ErrorBody {
+    reason: Option<String>,
+}
+
+async fn get_json<T: DeserializeOwned>(
+    path: &str,
+    fopts: FeedOption,
+    params: &[(&str, &str)],
+) -> Result<T, RedditError> {
+    let mut url = format!("https://www.reddit.com/{}.json", path);
+    fopts.build_url(&mut url);
+    for (key, val) in params {
+        let val: String = url::form_urlencoded::byte_serialize(val.as_bytes()).collect();
+        url.push_str(&format!("{}={}&", key, val));
+    }
+    url.push_str("raw_json=1"); // do not escape HTML entities
+    let resp = http_client()
+        .get(&url)
+        .send()
+        .await
+        .map_err(|e| RedditError::Network(e.to_string()))?;
+    // unknown subreddits are redirected to a search
+    if resp.url().path().starts_with("/subreddits/search") {
+        return Err(RedditError::NotFound);
+    }
+    let status = resp.status();
+    let body = resp
+        .text()
+        .await
+        .map_err(|e| RedditError::Network(e.to_string()))?;
+    if !status.is_success() {
+        let reason = serde_json::from_str::<ErrorBody>(&body)
+            .ok()
+            .and_then(|b| b.reason);
+        let user = path.starts_with("user/");
+        return Err(error_of(status, reason.as_deref(), user));
+    }
+    serde_json::from_str(&body).map_err(|e| RedditError::Parse(e.to_string()))
+}
+
+// Subreddit listed by the autocompletion
+#[derive(Debug, Deserialize)]
+struct SubredditName {
+    display_name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SubredditInfo {
+    pub display_name: String,
+    pub title: String,
+    pub subscribers: Option<u64>,
+    pub over18: Option<bool>,
+}
+
+impl fmt::Display for SubredditInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "r/{}", self.display_name)?;
+        if !self.title.is_empty() {
+            write!(f, ": {}", self.title)?;
+        }
+        if let Some(subscribers) = self.subscribers {
+            write!(f, " ({} subscribers)", subscribers)?;
+        }
+        if self.over18.unwrap_or(false) {
+            write!(f, " [NSFW]")?;
+        }
+        Ok(())
+    }
+}
+
+// Check that a subreddit exists, and describe it
+pub async fn subreddit_about(name: &str) -> Result<SubredditInfo, RedditError> {
+    let path = format!("r/{}/about", name);
+    let about: BasicThing<SubredditInfo> = get_json(&path, FeedOption::new(), &[]).await?;
+    Ok(about.data)
+}
+
+// Existing subreddits with a name similar to `name`
+pub async fn similar_subreddits(name: &str) -> Vec<String> {
+    let max_subs = 5;
+    let fopts = FeedOption::new().limit(max_subs);
+    let params = [("query", name), ("include_over_18", "false")];
+    let path = "api/subreddit_autocomplete_v2";
+    match get_json::<BasicListing<SubredditName>>(path, fopts, &params).await {
+        Ok(listing) => listing
+            .data
+            .children
+            .into_iter()
+            .map(|c| c.data.display_name)
+            .collect(),
+        Err(e) => {
+            log::info!("Cannot find subreddits similar to {}: {}", name, e);
+            Vec::new()
+        }
+    }
+}
+
+// Users are written as u/name, and their submissions are fetched instead of a subreddit
+pub fn feed_name(name: &str) -> String {
+    let name = name.trim().trim_start_matches('/');
+    match name
+        .strip_prefix("u/")
+        .or_else(|| name.strip_prefix("user/"))
+    {
+        Some(user) => format!("u/{}", user.trim()),
+        None => multireddit(name),
+    }
+}
+
+// Subreddits separated by '+' or commas are fetched as one merged feed (whitespaces are ignored)
+pub fn multireddit(name: &str) -> String {
+    let mut name = name.to_string();
+    name.retain(|c| !c.is_whitespace());
+    let subs: Vec<&str> = name.split(['+', ',']).filter(|s| !s.is_empty()).collect();
+    subs.join("+")
+}
+
+// Fetch the next page of up to `limit` posts
+async fn get_posts_raw(rcmd: &mut RedditCmd, limit: u32) -> Result<Vec<Post>, RedditError> {
+    let subreddit = multireddit(&rcmd.subreddit);
+    let mut fopts = FeedOption::new().limit(limit);
     if let Some(aft) = &rcmd.last_seen {
         fopts = fopts.after(aft); // seeing next page
     }
-    let view = match &rcmd.view {
-        RedReq::Hot => subreddit.hot(tot, Some(fopts)).await,
-        RedReq::Rise => subreddit.rising(tot, Some(fopts)).await,
-        rr => {
-            // Variants of Top command
-            fopts = match rr {
-                RedReq::TopD => fopts.period(TimePeriod::Today),
-                RedReq::TopW => fopts.period(TimePeriod::ThisWeek),
-                RedReq::TopM => fopts.period(TimePeriod::ThisMonth),
-                RedReq::TopY => fopts.period(TimePeriod::ThisYear),
-                RedReq::TopA => fopts.period(TimePeriod::AllTime),
-                _ => fopts, // unreachable
+    let (sort, period) = match &rcmd.view {
+        RedReq::Best => ("best", None),
+        RedReq::Hot => ("hot", None),
+        RedReq::New => ("new", None),
+        RedReq::Rise => ("rising", None),
+        RedReq::Top(period) => ("top", Some(period)),
+        RedReq::Controversial(period) => ("controversial", Some(period)),
+    };
+    // roux has no period for the past hour, set it directly
+    let mut params: Vec<(&str, &str)> = period.iter().map(|p| ("t", p.as_query())).collect();
+    let mut path = format!("r/{}/{}", subreddit, sort);
+    if let Some(user) = subreddit.strip_prefix("u/") {
+        // users have no best and rising listings
+        let sort = match sort {
+            "best" => "hot",
+            "rising" => "new",
+            sort => sort,
+        };
+        path = format!("user/{}/submitted", user);
+        params.push(("sort", sort));
+    }
+    if let Some(search) = &rcmd.search {
+        let sort = search.sort.unwrap_or(SearchSort::Relevance);
+        params = vec![("q", &search.query), ("sort", sort.as_query())];
+        params.extend(search.period.iter().map(|p| ("t", p.as_query())));
+        if subreddit.is_empty() || subreddit.eq_ignore_ascii_case("all") {
+            path = "search".to_string();
+        } else {
+            path = format!("r/{}/search", subreddit);
+            params.push(("restrict_sr", "1"));
+        }
+    }
+    let view: BasicListing<PostData> = get_json(&path, fopts, &params).await?;
+    rcmd.last_seen = view.data.after;
+    Ok(view.data.children)
+}
+
+// Media of a gallery post (or of the crossposted one), in display order
+fn gallery_items(post: &PostData) -> Vec<(String, FSFile, &'static str)> {
+    let parent = post.crosspost_parent_list.iter().flatten().next();
+    let (gallery, meta) = match (&post.gallery_data, &post.media_metadata, parent) {
+        (Some(gallery), Some(meta), _) => (gallery, meta),
+        (_, _, Some(parent)) => match (&parent.gallery_data, &parent.media_metadata) {
+            (Some(gallery), Some(meta)) => (gallery, meta),
+            _ => return Vec::new(),
+        },
+        _ => return Vec::new(),
+    };
+    let mut items = Vec::new();
+    for item in &gallery.items {
+        let media = match meta.get(&item.media_id) {
+            Some(media) if media.status.as_deref() == Some("valid") => media,
+            _ => continue,
+        };
+        let src = match &media.s {
+            Some(src) => src,
+            None => continue,
+        };
+        let video = FSFile::Video { f: "".to_string() };
+        let animated = media.e.as_deref() == Some("AnimatedImage");
+        let item = match (animated, &src.mp4, &src.u) {
+            (true, Some(mp4), _) => (mp4.clone(), video, "mp4"),
+            (false, _, Some(u)) => {
+                let mime = media.m.as_deref().unwrap_or("image/jpeg");
+                match kind_from_mime(mime) {
+                    Some((typ, ext)) => (u.clone(), typ, ext),
+                    None => continue,
+                }
+            }
+            // albums do not accept animations, GIFs are sent apart
+            _ => match &src.gif {
+                Some(gif) => (gif.clone(), FSFile::Animation { f: "".to_string() }, "gif"),
+                None => continue,
+            },
+        };
+        items.push(item);
+    }
+    items
+}
+
+// Sizes of the albums holding len items: split evenly, as albums need at least two items
+fn album_sizes(len: usize, max_album: usize) -> Vec<usize> {
+    let num_albums = len.div_ceil(max_album);
+    (0..num_albums)
+        .map(|i| len / num_albums + usize::from(i < len % num_albums))
+        .collect()
+}
+
+// Items of a gallery are cached one by one, as if they were sent alone
+async fn send_gallery(
+    items: Vec<(String, FSFile, &'static str)>,
+    bot: &Bot,
+    dest: Dest,
+    tit: &str,
+    max_mb: u64,
+    my_state: &MyState,
+    blur: bool,
+) -> Result<Option<Message>, teloxide::RequestError> {
+    let max_album = 10;
+    let max_size = max_mb * 1_048_576;
+    let cache = &my_state.my_conf.media_cache;
+    // (file, cached file_id, cache keys), only downloaded files have a name
+    let mut files = Vec::new();
+    for (url, typ, ext) in items {
+        // gallery items are never shrunk
+        let keys: Vec<String> = canonical_url(&url)
+            .map(|u| format!("{}#original", u))
+            .into_iter()
+            .collect();
+        if let Some((typ, file_id)) = lookup_cached(&keys, my_state).await {
+            files.push((typ, Some(file_id), keys));
+        } else if let Some(tmpfile) = download_as(&url, typ, ext, max_mb, cache).await? {
+            if fs::metadata(tmpfile.get_f())?.len() <= max_size {
+                files.push((tmpfile, None, keys));
+            } else {
+                std::fs::remove_file(tmpfile.get_f())?;
+            }
+        }
+    }
+    let (anims, files): (Vec<_>, Vec<_>) = files
+        .into_iter()
+        .partition(|(f, _, _)| matches!(f, FSFile::Animation { f: _ }));
+    let sizes = album_sizes(files.len(), max_album);
+    let num_albums = sizes.len();
+    let mut res = Ok(None);
+    let mut rest = files.as_slice();
+    for (i, size) in sizes.into_iter().enumerate() {
+        let (album, tail) = rest.split_at(size);
+        rest = tail;
+        let caption = match i {
+            0 => tit.to_string(),
+            _ => format!("{} ({}/{})", tit, i + 1, num_albums),
+        };
+        let sent = if album.len() == 1 {
+            let (typ, file_id, _) = &album[0];
+            typ.send_out(bot, dest, input_file(typ, file_id), &caption, blur)
+                .await
+                .map(|msg| vec![msg])
+        } else {
+            // the caption of the first item is shown as the album caption
+            let media: Vec<InputMedia> = album
+                .iter()
+                .enumerate()
+                .map(|(j, (typ, file_id, _))| {
+                    let fname = input_file(typ, file_id);
+                    typ.to_media(fname, (j == 0).then(|| caption.clone()), blur)
+                })
+                .collect();
+            bot.send_media_group(dest.chat_id, media)
+                .in_topic(dest.topic)
+                .await
+        };
+        match sent {
+            Ok(mut msgs) => {
+                for (msg, (_, _, keys)) in msgs.iter().zip(album) {
+                    cache_sent(msg, keys, my_state).await;
+                }
+                if i == 0 && !msgs.is_empty() {
+                    res = Ok(Some(msgs.remove(0)));
+                }
+            }
+            Err(e) => {
+                log::info!("Cannot send album: {}", e);
+                // some file_id might no longer be valid, upload them again next time
+                forget_cached(album, my_state).await;
+                if i == 0 {
+                    break;
+                }
+            }
+        }
+    }
+    let tit = tit.to_string();
+    for item @ (typ, file_id, keys) in &anims {
+        match typ
+            .send_out(bot, dest, input_file(typ, file_id), &tit, blur)
+            .await
+        {
+            Ok(msg) => {
+                cache_sent(&msg, keys, my_state).await;
+                if matches!(res, Ok(None)) {
+                    res = Ok(Some(msg));
+                }
+            }
+            Err(e) => {
+                log::info!("Cannot send animation: {}", e);
+                forget_cached(std::slice::from_ref(item), my_state).await;
+            }
+        }
+    }
+    for (typ, file_id, _) in files.iter().chain(&anims) {
+        if file_id.is_none() {
+            std::fs::remove_file(typ.get_f())?;
+        }
+    }
+    res
+}
+
+// Send a downloaded file, shrinking it to fit the upload limits if needed (and allowed).
+// Returns None if the file cannot be sent.
+async fn send_file(
+    tmpfile: &FSFile,
+    bot: &Bot,
+    dest: Dest,
+    tit: &String,
+    compress: Option<&MediaPipeline>,
+    blur: bool,
+) -> Result<Option<Message>, teloxide::RequestError> {
+    let max_size = 50 * 1_048_576; // 50 MiB
+    let max_photo_size = 10 * 1_048_576; // 10 MiB
+    let f = tmpfile.get_f();
+    let sz = fs::metadata(&f)?.len();
+    let res = match tmpfile {
+        FSFile::Image { f: _ } => {
+            if sz <= max_photo_size {
+                let res = bot
+                    .send_photo(dest.chat_id, InputFile::file(&f))
+                    .in_topic(dest.topic)
+                    .caption(tit)
+                    .parse_mode(ParseMode::Html)
+                    .has_spoiler(blur)
+                    .await;
+                if res.is_ok() {
+                    return Ok(res.ok());
+                }
+            }
+            // too big for a photo: downscale it...
+            for step in compress.iter().flat_map(|p| &p.image_ladder) {
+                let small = match transcode::shrink_image(&f, step).await {
+                    Some(small) => small,
+                    None => break,
+                };
+                let mut res = None;
+                if fs::metadata(&small)?.len() <= max_photo_size {
+                    let sent = bot
+                        .send_photo(dest.chat_id, InputFile::file(&small))
+                        .in_topic(dest.topic)
+                        .caption(tit)
+                        .parse_mode(ParseMode::Html)
+                        .has_spoiler(blur)
+                        .await;
+                    res = sent.ok();
+                }
+                std::fs::remove_file(small)?;
+                if res.is_some() {
+                    return Ok(res);
+                }
+            }
+            // ...or send it as document
+            match sz <= max_size {
+                true => {
+                    bot.send_document(dest.chat_id, InputFile::file(&f))
+                        .in_topic(dest.topic)
+                        .caption(tit)
+                        .parse_mode(ParseMode::Html)
+                        .await
+                }
+                false => return Ok(None),
+            }
+        }
+        FSFile::Video { f: _ } | FSFile::Animation { f: _ } if sz > max_size => {
+            let pipeline = match compress {
+                Some(pipeline) => pipeline,
+                None => return Ok(None),
             };
-            subreddit.top(tot, Some(fopts)).await
+            log::info!("File too big to be sent, re-encoding it.");
+            let small = match transcode::shrink_video(&f, max_size, &pipeline.video_ladder).await {
+                Some(small) => small,
+                None => return Ok(None),
+            };
+            let res = bot
+                .send_video(dest.chat_id, InputFile::file(&small))
+                .in_topic(dest.topic)
+                .caption(tit)
+                .parse_mode(ParseMode::Html)
+                .has_spoiler(blur)
+                .await;
+            std::fs::remove_file(small)?;
+            res
+        }
+        _ if sz > max_size => {
+            log::info!("File too big to be sent, sending URL instead.");
+            return Ok(None);
+        }
+        _ => {
+            tmpfile
+                .send_out(bot, dest, InputFile::file(&f), tit, blur)
+                .await
         }
     };
-    match view {
-        Ok(stuff) => {
-            rcmd.last_seen = stuff.data.after;
-            stuff.data.children
+    if let Err(e) = &res {
+        log::info!("Cannot send file: {}", e);
+    }
+    Ok(res.ok())
+}
+
+// Kind and file_id of a media already uploaded to Telegram, looking it up by the given keys
+async fn lookup_cached(keys: &[String], my_state: &MyState) -> Option<(FSFile, String)> {
+    let cache = &my_state.my_conf.media_cache;
+    if !cache.enabled {
+        return None;
+    }
+    let newer_than = scheduler::now() - cache.expiry_days as i64 * 86400;
+    for key in keys {
+        match persist::get_cached_media(&my_state.db, key, newer_than).await {
+            Ok(Some(media)) => {
+                if let Some(typ) = FSFile::from_kind(&media.kind) {
+                    return Some((typ, media.file_id));
+                }
+            }
+            Ok(None) => {}
+            Err(e) => {
+                log::error!("Cannot query media cache: {}", e);
+                return None;
+            }
         }
-        Err(_) => Vec::new(),
+    }
+    None
+}
+
+// Upload the downloaded file, or resend the cached one
+fn input_file(typ: &FSFile, file_id: &Option<String>) -> InputFile {
+    match file_id {
+        Some(file_id) => InputFile::file_id(file_id),
+        None => InputFile::file(typ.get_f()),
     }
 }
 
+// Drop the cached file_ids among the given gallery items
+async fn forget_cached(items: &[(FSFile, Option<String>, Vec<String>)], my_state: &MyState) {
+    for file_id in items.iter().filter_map(|(_, file_id, _)| file_id.as_ref()) {
+        if let Err(e) = persist::forget_media(&my_state.db, file_id).await {
+            log::error!("Cannot update media cache: {}", e);
+        }
+    }
+}
+
+// Resend a media already uploaded to Telegram, looking it up by the given keys
+async fn send_cached(
+    keys: &[String],
+    bot: &Bot,
+    dest: Dest,
+    tit: &String,
+    my_state: &MyState,
+    blur: bool,
+) -> Result<Option<Message>, teloxide::RequestError> {
+    for key in keys {
+        let (typ, file_id) = match lookup_cached(std::slice::from_ref(key), my_state).await {
+            Some(found) => found,
+            None => continue,
+        };
+        let fname = InputFile::file_id(&file_id);
+        match typ.send_out(bot, dest, fname, tit, blur).await {
+            Ok(msg) => return Ok(Some(msg)),
+            Err(e) => {
+                // the file_id is no longer valid, upload the file again
+                log::info!("Cannot resend cached media: {}", e);
+                if let Err(e) = persist::forget_media(&my_state.db, &file_id).await {
+                    log::error!("Cannot update media cache: {}", e);
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+// Save the file_id of the media sent in `msg`, to resend it later
+async fn cache_sent(msg: &Message, keys: &[String], my_state: &MyState) {
+    if !my_state.my_conf.media_cache.enabled {
+        return;
+    }
+    if let Some((kind, file_id)) = cache::sent_media(msg) {
+        let media = persist::CachedMedia {
+            kind: kind.to_string(),
+            file_id,
+        };
+        let res = persist::cache_media(&my_state.db, keys, &media, scheduler::now()).await;
+        if let Err(e) = res {
+            log::error!("Cannot update media cache: {}", e);
+        }
+    }
+}
+
+// Send a post to dest, following the settings of chat_id (usually the same chat)
 pub async fn send_post(
-    post: BasicThing<SubmissionData>,
+    post: Post,
     bot: Bot,
     chat_id: ChatId,
-    url_matches: &UrlMatches,
+    dest: Dest,
+    my_state: &MyState,
+    blur: bool,
 ) -> Result<Message, teloxide::RequestError> {
+    let url_matches = &my_state.my_conf.url_matches;
+    let pipeline = &my_state.my_conf.media_pipeline;
+    let cache = &my_state.my_conf.media_cache;
+    let mode = persist::get_setting(&my_state.db, chat_id, "large_media")
+        .await
+        .unwrap_or_default();
+    // shrink large media or send them as they are?
+    let compress = match mode.as_deref() {
+        None | Some("compressed") if pipeline.enabled => Some(pipeline),
+        _ => None,
+    };
     let max_mb = 50; // 50 MiB
-    let max_size = max_mb * 1_048_576;
-    let tit = post.data.title;
-    let url = post.data.url.unwrap_or_default(); // defaults to ""
-    let alt_msg = format!("{}\n{}", &tit, &url);
-    if url.is_empty() {
-        bot.send_message(chat_id, &tit).await
+    let dl_mb = match compress {
+        Some(pipeline) => pipeline.max_download_mb.max(max_mb),
+        None => max_mb,
+    };
+    let gallery = gallery_items(&post.data);
+    let rvideo = reddit_video(&post.data).cloned();
+    let is_self = post.data.base.is_self;
+    let permalink = format!("https://www.reddit.com{}", post.data.base.permalink);
+    let template = persist::get_setting(&my_state.db, chat_id, "caption")
+        .await
+        .unwrap_or_default()
+        .unwrap_or_else(|| my_state.my_conf.caption_template.clone());
+    let fields = caption::fields(&post.data.base);
+    let mut tit = caption::render(&template, &fields, caption::MAX_LEN);
+    let title = post.data.base.title;
+    let url = post.data.base.url.unwrap_or_default(); // defaults to ""
+    let alt_msg = match blur {
+        true => format!(
+            "{}\n<tg-spoiler>{}</tg-spoiler>",
+            &tit,
+            markdown::escape(&url)
+        ),
+        false => format!("{}\n{}", &tit, markdown::escape(&url)),
+    };
+    if !gallery.is_empty() {
+        match send_gallery(gallery, &bot, dest, &tit, max_mb, my_state, blur).await? {
+            Some(msg) => Ok(msg),
+            None => send_html(&bot, dest, alt_msg, blur).await,
+        }
+    } else if url.is_empty() || is_self {
+        let selftext = post.data.base.selftext;
+        send_self_post(&bot, dest, &tit, &title, &selftext, &permalink, blur).await
     } else {
-        let mut res;
-        let tmpfile = download(&url, max_mb, url_matches).await?;
-        if let Some(tmpfile) = tmpfile {
-            let f = tmpfile.get_f();
-            let sz = fs::metadata(&f)?.len();
-            if sz > max_size {
-                log::info!("File too big to be sent, sending URL instead.");
-                res = bot.send_message(chat_id, alt_msg).await;
-            } else {
-                let fname = InputFile::file(&f);
-                res = tmpfile.send_out(&bot, chat_id, fname, &tit).await;
-                if res.is_err() {
-                    log::info!("Cannot send file: {}", res.unwrap_err());
-                    res = bot.send_message(chat_id, alt_msg).await;
+        // the media sent depends on the compression mode
+        let mode = match compress {
+            Some(_) => "compressed",
+            None => "original",
+        };
+        let mut keys: Vec<String> = canonical_url(&url)
+            .map(|u| format!("{}#{}", u, mode))
+            .into_iter()
+            .collect();
+        if let Some(msg) = send_cached(&keys, &bot, dest, &tit, my_state, blur).await? {
+            return Ok(msg);
+        }
+        let mut tmpfile = None;
+        if let Some(rvideo) = rvideo {
+            if let Some((video, lost_audio)) = download_reddit_video(&rvideo, dl_mb).await? {
+                if lost_audio {
+                    tit.push_str("\n(sent without audio)");
                 }
+                tmpfile = Some(video);
+            }
+        }
+        if tmpfile.is_none() {
+            tmpfile = download(&url, dl_mb, url_matches, cache).await?;
+        }
+        if let Some(tmpfile) = tmpfile {
+            // the same content might have been sent from another URL
+            let mut res = None;
+            if cache.enabled {
+                let hash_key = format!(
+                    "sha256:{}#{}",
+                    cache::hash_file(&tmpfile.get_f()).await?,
+                    mode
+                );
+                let hash_keys = std::slice::from_ref(&hash_key);
+                res = send_cached(hash_keys, &bot, dest, &tit, my_state, blur).await?;
+                keys.push(hash_key);
+            }
+            if res.is_none() {
+                res = send_file(&tmpfile, &bot, dest, &tit, compress, blur).await?;
+            }
+            if let Some(msg) = &res {
+                cache_sent(msg, &keys, my_state).await;
+            }
+            std::fs::remove_file(tmpfile.get_f())?;
+            match res {
+                Some(msg) => Ok(msg),
+                None => send_html(&bot, dest, alt_msg, blur).await,
             }
-            std::fs::remove_file(f)?;
-            res
         } else {
-            bot.send_message(chat_id, alt_msg).await
+            send_html(&bot, dest, alt_msg, blur).await
+        }
+    }
+}
+
+// Blurred messages have no link preview, which would show the media
+async fn send_html(
+    bot: &Bot,
+    dest: Dest,
+    txt: String,
+    blur: bool,
+) -> Result<Message, teloxide::RequestError> {
+    bot.send_message(dest.chat_id, txt)
+        .in_topic(dest.topic)
+        .parse_mode(ParseMode::Html)
+        .disable_web_page_preview(blur)
+        .await
+}
+
+// Send caption and text of a self post, split in a few messages.
+// Longer texts are truncated, with a button linking to the post.
+async fn send_self_post(
+    bot: &Bot,
+    dest: Dest,
+    tit: &str,
+    title: &str,
+    selftext: &str,
+    permalink: &str,
+    blur: bool,
+) -> Result<Message, teloxide::RequestError> {
+    let max_len = 4096;
+    let max_msgs = 3;
+    let mut blocks = vec![tit.to_string()];
+    blocks.extend(markdown::to_html(selftext, max_len, blur));
+    let mut pages = markdown::paginate(&blocks, max_len);
+    let truncated = pages.len() > max_msgs;
+    pages.truncate(max_msgs);
+    let mut first = None;
+    for (i, page) in pages.iter().enumerate() {
+        let mut req = bot
+            .send_message(dest.chat_id, page)
+            .in_topic(dest.topic)
+            .parse_mode(ParseMode::Html)
+            .disable_web_page_preview(true);
+        if truncated && i == pages.len() - 1 {
+            if let Ok(url) = Url::parse(permalink) {
+                let more = InlineKeyboardButton::url("Read more", url);
+                req = req.reply_markup(InlineKeyboardMarkup::new([[more]]));
+            }
+        }
+        match req.await {
+            Ok(msg) => {
+                first.get_or_insert(msg);
+            }
+            Err(e) => {
+                // If HTML cannot be parsed, send the title and the link as raw text
+                log::info!("Cannot send text post: {}", e);
+                let msg = bot
+                    .send_message(dest.chat_id, format!("{}\n{}", title, permalink))
+                    .in_topic(dest.topic)
+                    .await?;
+                first.get_or_insert(msg);
+                break;
+            }
         }
     }
+    match first {
+        Some(msg) => Ok(msg),
+        None => {
+            bot.send_message(dest.chat_id, title)
+                .in_topic(dest.topic)
+                .await
+        }
+    }
+}
+
+// Reddit-hosted video of a post (or of the crossposted one)
+fn reddit_video(post: &PostData) -> Option<&RedditVideo> {
+    let parent = post.crosspost_parent_list.iter().flatten().next();
+    let media = post
+        .secure_media
+        .as_ref()
+        .or_else(|| parent.and_then(|p| p.secure_media.as_ref()));
+    media.and_then(|m| m.reddit_video.as_ref())
 }
 
+// Download the best video stream fitting in max_mb and, if available, merge its audio.
+// Returns the video and whether its audio track has been lost.
+async fn download_reddit_video(
+    rvideo: &RedditVideo,
+    max_mb: u64,
+) -> Result<Option<(FSFile, bool)>, teloxide::RequestError> {
+    let max_size = max_mb * 1_048_576;
+    let mut reps = Vec::new();
+    if let Some(Ok(dash_url)) = rvideo.dash_url.as_deref().map(Url::parse) {
+        let resp = http_client().get(dash_url.clone()).send().await;
+        match resp.and_then(|r| r.error_for_status()) {
+            Ok(resp) => {
+                let mpd = resp.text().await.unwrap_or_default();
+                reps = dash::parse_mpd(&mpd, &dash_url);
+            }
+            Err(e) => log::info!("Cannot get DASH playlist: {}", e),
+        }
+    }
+    let duration = rvideo.duration.unwrap_or(0.0);
+    let (videos, audio) = dash::pick(&reps, duration, max_size);
+    let mut urls: Vec<&String> = videos.iter().map(|v| &v.url).collect();
+    urls.extend(&rvideo.fallback_url);
+    let mut vfile = None;
+    for url in urls {
+        let f = tmp_name("mp4")?;
+        if fetch_file(url, &f, max_size).await? {
+            vfile = Some(f);
+            break;
+        }
+    }
+    let vfile = match vfile {
+        Some(f) => f,
+        None => return Ok(None),
+    };
+    let audio = match audio {
+        Some(audio) => audio,
+        // the video is silent
+        None if !reps.is_empty() => return Ok(Some((FSFile::Video { f: vfile }, false))),
+        // no playlist, audio is unknown
+        None => return Ok(Some((FSFile::Video { f: vfile }, true))),
+    };
+    let afile = tmp_name("m4a")?;
+    let mut muxed = false;
+    let out = tmp_name("mp4")?;
+    if fetch_file(&audio.url, &afile, max_size).await? {
+        muxed = dash::mux(&vfile, &afile, &out).await;
+        std::fs::remove_file(&afile)?;
+    }
+    if muxed {
+        std::fs::remove_file(&vfile)?;
+        Ok(Some((FSFile::Video { f: out }, false)))
+    } else {
+        let _ = std::fs::remove_file(&out);
+        Ok(Some((FSFile::Video { f: vfile }, true)))
+    }
+}
+
+// URL without scheme, "www." and fragment, to compare links to the same media
+fn canonical_url(url: &str) -> Option<String> {
+    let mut url = Url::parse(url).ok()?;
+    url.set_fragment(None);
+    let host = url.host_str().unwrap_or_default();
+    let host = host.trim_start_matches("www.").to_string();
+    let path = url.path().trim_end_matches('/').to_string();
+    // previews of Reddit images only differ by their size, given in the query
+    if host == "i.redd.it" || host == "preview.redd.it" {
+        return Some(format!("i.redd.it{}", path));
+    }
+    let query = url.query().map(|q| format!("?{}", q)).unwrap_or_default();
+    Some(format!("{}{}{}", host, path, query))
+}
+
+// Keys identifying a post: its fullname and, to catch crossposts, its media URL
+fn seen_keys(post: &Post) -> Vec<String> {
+    let mut keys = vec![post.data.base.name.clone()];
+    // a crosspost is the same as its original
+    keys.extend(post.data.crosspost_parent.iter().cloned());
+    let post = &post.data.base;
+    if !post.is_self {
+        let url = post.url.as_deref().unwrap_or_default();
+        keys.extend(canonical_url(url));
+    }
+    keys
+}
+
+pub async fn nsfw_policy(my_state: &MyState, chat_id: ChatId) -> Result<NsfwPolicy> {
+    let policy = persist::get_setting(&my_state.db, chat_id, "nsfw")
+        .await?
+        .and_then(|v| NsfwPolicy::from_str(&v).ok())
+        .unwrap_or(my_state.my_conf.nsfw_policy);
+    Ok(policy)
+}
+
+// The newest posts of a subreddit (or multireddit)
+pub async fn new_posts(subreddit: &str, tot: u32) -> Result<Vec<Post>, RedditError> {
+    let mut rcmd = RedditCmd {
+        view: RedReq::New,
+        subreddit: subreddit.to_string(),
+        tot,
+        category: "".to_string(),
+        last_seen: None,
+        search: None,
+    };
+    get_posts_raw(&mut rcmd, tot).await
+}
+
+// Send a page of posts to dest, following the settings of chat_id (usually the same chat).
+// Returns how many posts were sent, fewer than asked if not enough fresh ones were found
 pub async fn send_posts(
     bot: Bot,
     chat_id: ChatId,
+    dest: Dest,
     rcmd: &mut RedditCmd,
-    url_matches: &UrlMatches,
-) -> Result<()> {
-    let p_raw = get_posts_raw(rcmd).await;
-    let mut posts_sent = Vec::new();
-    for post in p_raw {
-        if post.data.stickied {
+    my_state: &MyState,
+    sub_id: Option<i64>,
+) -> Result<usize> {
+    let max_pages = 5;
+    // fetch more posts than needed, as some might be skipped (Reddit pages are up to 100)
+    let page_size = rcmd.tot.clamp(25, 100);
+    let db = &my_state.db;
+    let skip_seen = persist::get_setting(db, chat_id, "skip_seen")
+        .await?
+        .is_none_or(|v| v == "on");
+    let nsfw = nsfw_policy(my_state, chat_id).await?;
+    // the filters of the chat, plus the ones of the subscription
+    let mut filters = vec![persist::get_filters(db, chat_id, None).await?];
+    if sub_id.is_some() {
+        filters.push(persist::get_filters(db, chat_id, sub_id).await?);
+    }
+    let filters: Vec<Filter> = filters.iter().filter_map(|f| f.compile().ok()).collect();
+    // keep paging until enough fresh posts are found
+    let mut fresh: Vec<(Post, Vec<String>)> = Vec::new();
+    let mut batch_keys: Vec<String> = Vec::new();
+    for _ in 0..max_pages {
+        let p_raw = match get_posts_raw(rcmd, page_size).await {
+            Ok(p_raw) => p_raw,
+            // report the error, unless some posts can be shown
+            Err(e) if fresh.is_empty() => return Err(e.into()),
+            Err(e) => {
+                log::info!("Cannot fetch more posts: {}", e);
+                break;
+            }
+        };
+        let last_page = p_raw.is_empty() || rcmd.last_seen.is_none();
+        for post in p_raw {
+            if fresh.len() as u32 >= rcmd.tot {
+                // resume from the last sent post when showing the next page
+                rcmd.last_seen = fresh.last().map(|(p, _)| p.data.base.name.clone());
+                break;
+            }
+            if post.data.base.stickied || (nsfw == NsfwPolicy::Hide && post.data.is_sensitive()) {
+                continue;
+            }
+            if !filters.iter().all(|f| f.accepts(&post.data.base)) {
+                continue;
+            }
+            let keys = seen_keys(&post);
+            // the same link might be posted in several subreddits of a multireddit
+            if keys.iter().any(|k| batch_keys.contains(k)) {
+                continue;
+            }
+            if skip_seen && persist::is_seen(db, dest.chat_id, &keys).await? {
+                continue;
+            }
+            batch_keys.extend(keys.iter().cloned());
+            fresh.push((post, keys));
+        }
+        if last_page || fresh.len() as u32 >= rcmd.tot {
+            break;
+        }
+    }
+    let sent = fresh.len();
+    for (post, keys) in fresh {
+        let post_id = post.data.base.id.clone();
+        let num_comments = post.data.base.num_comments;
+        let blur = nsfw == NsfwPolicy::Blur && post.data.is_sensitive();
+        let msg = send_post(post, bot.clone(), chat_id, dest, my_state, blur).await?;
+        if num_comments > 0 {
+            add_comments_button(&bot, &msg, &post_id, my_state).await;
+        }
+        if skip_seen {
+            persist::mark_seen(db, dest.chat_id, &keys, scheduler::now()).await?;
+        }
+    }
+    Ok(sent)
+}
+
+// A page of posts as inline query results, to be shared in any chat
+pub async fn inline_results(
+    rcmd: &mut RedditCmd,
+    my_state: &MyState,
+    chat_id: ChatId,
+) -> Result<Vec<InlineQueryResult>> {
+    let db = &my_state.db;
+    let nsfw = nsfw_policy(my_state, chat_id).await?;
+    let filter = persist::get_filters(db, chat_id, None)
+        .await?
+        .compile()
+        .ok();
+    let template = persist::get_setting(db, chat_id, "caption")
+        .await?
+        .unwrap_or_else(|| my_state.my_conf.caption_template.clone());
+    let mut results = Vec::new();
+    let tot = rcmd.tot;
+    for post in get_posts_raw(rcmd, tot).await? {
+        let data = &post.data;
+        let base = &data.base;
+        let sensitive = data.is_sensitive();
+        if base.stickied || (nsfw == NsfwPolicy::Hide && sensitive) {
             continue;
         }
-        let sent = send_post(post, bot.clone(), chat_id, url_matches);
-        posts_sent.push(sent);
+        if filter.as_ref().is_some_and(|f| !f.accepts(base)) {
+            continue;
+        }
+        let blur = nsfw == NsfwPolicy::Blur && sensitive;
+        let tit = caption::render(&template, &caption::fields(base), caption::MAX_LEN);
+        let description = format!(
+            "r/{} \u{2022} {} points \u{2022} {} comments",
+            base.subreddit, base.score, base.num_comments
+        );
+        // blurred posts have no thumbnail, which would show the media
+        let thumb = data
+            .thumbnail()
+            .filter(|_| !blur)
+            .and_then(|t| Url::parse(&t.url).ok().map(|u| (u, t)));
+        let url = base.url.clone().unwrap_or_default();
+        // Telegram only accepts JPEG photos
+        let photo = Url::parse(&url)
+            .ok()
+            .filter(|u| u.path().ends_with(".jpg") || u.path().ends_with(".jpeg"));
+        let result = match (photo, thumb) {
+            (Some(photo), Some((thumb, _))) => {
+                let photo = InlineQueryResultPhoto::new(&base.name, photo, thumb)
+                    .title(&base.title)
+                    .description(description)
+                    .caption(tit)
+                    .parse_mode(ParseMode::Html);
+                InlineQueryResult::Photo(photo)
+            }
+            (_, thumb) => {
+                let url = markdown::escape(&url);
+                let txt = match blur {
+                    true => format!("{}\n<tg-spoiler>{}</tg-spoiler>", tit, url),
+                    false => format!("{}\n{}", tit, url),
+                };
+                let content = InputMessageContentText::new(txt)
+                    .parse_mode(ParseMode::Html)
+                    .disable_web_page_preview(blur);
+                let mut article = InlineQueryResultArticle::new(
+                    &base.name,
+                    &base.title,
+                    InputMessageContent::Text(content),
+                )
+                .description(description);
+                if let Some((url, t)) = thumb {
+                    article = article
+                        .thumb_url(url)
+                        .thumb_width(t.width)
+                        .thumb_height(t.height);
+                }
+                InlineQueryResult::Article(article)
+            }
+        };
+        results.push(result);
     }
-    for sent in posts_sent {
-        sent.await?;
+    Ok(results)
+}
+
+// Add a button to show the comments under a sent post
+async fn add_comments_button(bot: &Bot, msg: &Message, post_id: &str, my_state: &MyState) {
+    let text = "\u{1F4AC} Comments";
+    // in channels, the comments would be shown to every subscriber: link them instead
+    let button = if msg.chat.is_channel() {
+        let permalink = format!("https://www.reddit.com/comments/{}", post_id);
+        match Url::parse(&permalink) {
+            Ok(url) => InlineKeyboardButton::url(text, url),
+            Err(_) => return,
+        }
+    } else {
+        let action = Action::Comments(post_id.to_string(), 0);
+        match callback::encode(&my_state.db, &action).await {
+            Ok(data) => InlineKeyboardButton::callback(text, data),
+            Err(e) => {
+                log::info!("Cannot encode comments button: {}", e);
+                return;
+            }
+        }
+    };
+    let mut buttons = msg
+        .reply_markup()
+        .map(|m| m.inline_keyboard.clone())
+        .unwrap_or_default();
+    buttons.push(vec![button]);
+    let res = bot
+        .edit_message_reply_markup(msg.chat.id, msg.id)
+        .reply_markup(InlineKeyboardMarkup::new(buttons))
+        .await;
+    // e.g., albums cannot have buttons
+    if let Err(e) = res {
+        log::info!("Cannot add comments button: {}", e);
     }
-    Ok(())
 }
 
-fn get_type(url: &str, url_matches: &UrlMatches) -> Option<FSFile> {
-    let mut is_video = false;
-    for s in &url_matches.video.endings {
-        is_video |= url.ends_with(s);
+fn flatten_comments(listing: &BasicListing<CommentData>, depth: usize, out: &mut Vec<Comment>) {
+    for child in &listing.data.children {
+        let c = &child.data;
+        // skip "load more" stubs and moderator announcements
+        let body = match &c.body {
+            Some(body) if c.stickied != Some(true) => body,
+            _ => continue,
+        };
+        out.push(Comment {
+            depth,
+            author: c.author.clone().unwrap_or_else(|| "[deleted]".to_string()),
+            score: c.score.unwrap_or(0),
+            body: body.clone(),
+        });
+        if let Some(MaybeReplies::Reply(replies)) = &c.replies {
+            flatten_comments(replies, depth + 1, out);
+        }
     }
-    for s in &url_matches.video.starts {
-        is_video |= url.starts_with(s);
+}
+
+fn render_comment(c: &Comment, max_body: usize, max_len: usize) -> String {
+    let indent = "\u{2502} ".repeat(c.depth);
+    let mut body: String = c.body.chars().take(max_body).collect();
+    if body.len() < c.body.len() {
+        body.push('\u{2026}');
     }
-    if is_video {
-        return Some(FSFile::Video { f: "".to_string() });
+    let mut html = format!(
+        "{}<b>u/{}</b> ({} points)",
+        indent,
+        markdown::escape(&c.author),
+        c.score
+    );
+    for line in markdown::to_html(&body, max_len, false).join("\n").lines() {
+        html.push('\n');
+        html.push_str(&indent);
+        html.push_str(line);
     }
-    let mut is_image = false;
-    for s in &url_matches.image.endings {
-        is_image |= url.ends_with(s);
+    html
+}
+
+// Top comments of a post, with their replies, as HTML messages
+pub async fn comment_pages(post_id: &str) -> Vec<String> {
+    let max_comments = 20;
+    let max_depth = 3;
+    let max_body = 800;
+    let max_len = 4096;
+    let url = format!(
+        "https://www.reddit.com/comments/{}.json?sort=top&limit={}&depth={}&raw_json=1",
+        post_id, max_comments, max_depth
+    );
+    let resp = http_client()
+        .get(&url)
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status());
+    // the post itself, followed by its comments
+    let listings: Vec<BasicListing<CommentData>> = match resp {
+        Ok(resp) => resp.json().await.unwrap_or_default(),
+        Err(e) => {
+            log::info!("Cannot get comments of {}: {}", post_id, e);
+            Vec::new()
+        }
+    };
+    let mut comments = Vec::new();
+    if let Some(listing) = listings.last() {
+        flatten_comments(listing, 0, &mut comments);
     }
-    for s in &url_matches.image.starts {
-        is_image |= url.starts_with(s);
+    if comments.is_empty() {
+        return Vec::new();
     }
-    if is_image {
-        return Some(FSFile::Image { f: "".to_string() });
+    let mut blocks: Vec<String> = comments
+        .iter()
+        .map(|c| render_comment(c, max_body, max_len))
+        .collect();
+    // the "load more" stubs are skipped: point to the full thread
+    blocks.push(format!(
+        "<i>Only the top {} comments are shown, down to {} levels of replies. \
+         <a href=\"https://www.reddit.com/comments/{}\">Read all of them on Reddit</a>.</i>",
+        max_comments, max_depth, post_id
+    ));
+    markdown::paginate(&blocks, max_len)
+}
+
+// Pages from which yt-dlp can extract a video
+fn is_video_page(url: &str, url_matches: &UrlMatches) -> bool {
+    let video = &url_matches.video;
+    video.endings.iter().any(|s| url.ends_with(s))
+        || video.starts.iter().any(|s| url.starts_with(s))
+}
+
+fn kind_from_mime(mime: &str) -> Option<(FSFile, &'static str)> {
+    let f = "".to_string();
+    let mime = mime.split(';').next().unwrap_or_default().trim();
+    let kind = match mime {
+        "image/jpeg" | "image/jpg" => (FSFile::Image { f }, "jpg"),
+        "image/png" => (FSFile::Image { f }, "png"),
+        "image/webp" => (FSFile::Image { f }, "webp"),
+        "image/gif" => (FSFile::Animation { f }, "gif"),
+        "video/mp4" => (FSFile::Video { f }, "mp4"),
+        "video/webm" => (FSFile::Video { f }, "webm"),
+        "video/quicktime" => (FSFile::Video { f }, "mov"),
+        "audio/mpeg" | "audio/mp3" => (FSFile::Audio { f }, "mp3"),
+        "audio/mp4" | "audio/x-m4a" => (FSFile::Audio { f }, "m4a"),
+        "audio/ogg" => (FSFile::Audio { f }, "ogg"),
+        "audio/flac" => (FSFile::Audio { f }, "flac"),
+        "application/pdf" => (FSFile::Document { f }, "pdf"),
+        _ => return None,
+    };
+    Some(kind)
+}
+
+// Kind and extension of a file, from its first bytes or, as a fallback, its Content-Type
+fn sniff(head: &[u8], ctype: &str) -> Option<(FSFile, &'static str)> {
+    let f = "".to_string();
+    let kind = if head.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        (FSFile::Image { f }, "jpg")
+    } else if head.starts_with(b"\x89PNG") {
+        (FSFile::Image { f }, "png")
+    } else if head.starts_with(b"RIFF") && head.get(8..12) == Some(b"WEBP") {
+        (FSFile::Image { f }, "webp")
+    } else if head.starts_with(b"GIF8") {
+        (FSFile::Animation { f }, "gif")
+    } else if head.get(4..8) == Some(b"ftyp") {
+        match head.get(8..11) {
+            Some(b"M4A") => (FSFile::Audio { f }, "m4a"),
+            Some(b"qt ") => (FSFile::Video { f }, "mov"),
+            _ => (FSFile::Video { f }, "mp4"),
+        }
+    } else if head.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]) {
+        (FSFile::Video { f }, "webm")
+    } else if head.starts_with(b"ID3") || matches!(head, [0xFF, 0xFB | 0xF3 | 0xF2, ..]) {
+        (FSFile::Audio { f }, "mp3")
+    } else if head.starts_with(b"OggS") {
+        (FSFile::Audio { f }, "ogg")
+    } else if head.starts_with(b"fLaC") {
+        (FSFile::Audio { f }, "flac")
+    } else if head.starts_with(b"%PDF") {
+        (FSFile::Document { f }, "pdf")
+    } else {
+        return kind_from_mime(ctype);
+    };
+    Some(kind)
+}
+
+// Fetch the first bytes of `url` to find out what it is
+async fn classify(url: &str) -> Option<(FSFile, &'static str)> {
+    let head_size = 64;
+    let resp = http_client()
+        .get(url)
+        .header(RANGE, format!("bytes=0-{}", head_size - 1))
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status());
+    let mut resp = match resp {
+        Ok(resp) => resp,
+        Err(e) => {
+            log::info!("Cannot inspect {}: {}", url, e);
+            return None;
+        }
+    };
+    let ctype = resp
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    // the server might ignore the range, stop reading anyway
+    let mut head = Vec::new();
+    while head.len() < head_size {
+        match resp.chunk().await {
+            Ok(Some(chunk)) => head.extend_from_slice(&chunk),
+            _ => break,
+        }
     }
-    None
+    sniff(&head, &ctype)
 }
 
 async fn download(
     url: &str,
     max_mb: u64,
     url_matches: &UrlMatches,
+    cache: &MediaCache,
 ) -> Result<Option<FSFile>, teloxide::RequestError> {
     let check = Url::parse(url);
-    let max_sz = format!("{}M", max_mb);
     // allow only proper https urls
     if check.is_err() || check.unwrap().scheme() != "https" {
         return Ok(None);
     }
-    // command arguments
-    let downloader;
-    let save_as;
-    let ext;
-    let typ = get_type(url, url_matches);
-    if typ.is_none() {
-        return Ok(None);
+    match classify(url).await {
+        Some((typ, ext)) => download_as(url, typ, ext, max_mb, cache).await,
+        None if is_video_page(url, url_matches) => download_video_page(url, max_mb).await,
+        None => Ok(None),
     }
-    let mut args = Vec::new();
-    let typ = typ.unwrap();
-    // image or video?
-    match typ {
-        FSFile::Image { f: _ } => {
-            downloader = "wget";
-            save_as = "-O";
-            ext = ".jpg";
+}
+
+// Stream `url` into `fname`, checking its size
+async fn fetch_file(url: &str, fname: &str, max_size: u64) -> Result<bool, teloxide::RequestError> {
+    let resp = http_client()
+        .get(url)
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status());
+    let mut resp = match resp {
+        Ok(resp) => resp,
+        Err(e) => {
+            log::info!("Cannot download {}: {}", url, e);
+            return Ok(false);
         }
-        FSFile::Video { f: _ } => {
-            downloader = "yt-dlp";
-            save_as = "-o";
-            ext = ".mp4";
-            args.push("--max-filesize");
-            args.push(&max_sz);
+    };
+    if resp.content_length().is_some_and(|len| len > max_size) {
+        log::info!("Not downloading {}: file too big", url);
+        return Ok(false);
+    }
+    let mut dst = tokio::fs::File::create(fname).await?;
+    let mut size = 0;
+    loop {
+        let chunk = match resp.chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(e) => {
+                log::info!("Error while downloading {}: {}", url, e);
+                size = u64::MAX;
+                break;
+            }
+        };
+        size += chunk.len() as u64;
+        if size > max_size {
+            log::info!("Stopped downloading {}: file too big", url);
+            break;
         }
+        dst.write_all(&chunk).await?;
+    }
+    dst.flush().await?;
+    if size > max_size {
+        drop(dst);
+        std::fs::remove_file(fname)?;
+        return Ok(false);
     }
+    Ok(true)
+}
+
+fn tmp_name(ext: &str) -> Result<String, teloxide::RequestError> {
     let base_dir = "/tmp/red_fetch/";
     fs::create_dir_all(base_dir)?;
-    let tmpfile = format!("{}{}{}", base_dir, Uuid::new_v4(), ext);
-    let mut new_args = vec!["-q", save_as, &tmpfile, url];
-    args.append(&mut new_args);
-    let child = Command::new(downloader).args(args).spawn();
+    Ok(format!("{}{}.{}", base_dir, Uuid::new_v4(), ext))
+}
+
+async fn download_as(
+    url: &str,
+    typ: FSFile,
+    ext: &str,
+    max_mb: u64,
+    cache: &MediaCache,
+) -> Result<Option<FSFile>, teloxide::RequestError> {
+    let tmpfile = tmp_name(ext)?;
+    let max_size = max_mb * 1_048_576;
+    let done =
+        cache::disk_fetch(url, ext, &tmpfile, cache) && fs::metadata(&tmpfile)?.len() <= max_size;
+    if done && is_kind(&tmpfile, &typ)? {
+        return Ok(Some(typ.with_f(tmpfile)));
+    }
+    let _ = fs::remove_file(&tmpfile);
+    if !fetch_file(url, &tmpfile, max_size).await? {
+        return Ok(None);
+    }
+    // e.g., an HTML error page, or the landing page of an image host
+    if !is_kind(&tmpfile, &typ)? {
+        log::info!("Not sending {}: unexpected content", url);
+        std::fs::remove_file(&tmpfile)?;
+        return Ok(None);
+    }
+    cache::disk_store(url, ext, &tmpfile, cache).await;
+    Ok(Some(typ.with_f(tmpfile)))
+}
+
+// Whether a downloaded file is of the given kind, judging from its first bytes
+fn is_kind(fname: &str, typ: &FSFile) -> Result<bool, std::io::Error> {
+    let mut head = [0; 64];
+    let len = fs::File::open(fname)?.read(&mut head)?;
+    let same = |(kind, _): (FSFile, _)| mem::discriminant(&kind) == mem::discriminant(typ);
+    Ok(sniff(&head[..len], "").is_some_and(same))
+}
+
+// Videos embedded in web pages are extracted by yt-dlp
+async fn download_video_page(
+    url: &str,
+    max_mb: u64,
+) -> Result<Option<FSFile>, teloxide::RequestError> {
+    let tmpfile = tmp_name("mp4")?;
+    let max_sz = format!("{}M", max_mb);
+    let args = ["-q", "--max-filesize", &max_sz, "-o", &tmpfile, url];
+    let child = Command::new("yt-dlp").args(args).spawn();
     if child.is_err() {
         return Ok(None);
     };
@@ -236,11 +1805,274 @@ async fn download(
     let status = child.unwrap().wait().await?;
     let md = fs::metadata(&tmpfile);
     if status.success() && md.is_ok() {
-        match typ {
-            FSFile::Image { f: _ } => Ok(Some(FSFile::Image { f: tmpfile })),
-            FSFile::Video { f: _ } => Ok(Some(FSFile::Video { f: tmpfile })),
-        }
+        Ok(Some(FSFile::Video { f: tmpfile }))
     } else {
         Ok(None)
     }
 }
+
+// A post with the given fields, the others set to some defaults
+#[cfg(test)]
+pub fn test_post(fields: serde_json::Value) -> Post {
+    let mut data = serde_json::json!({
+        "subreddit": "rust",
+        "selftext": "",
+        "id": "abc123",
+        "gilded": 0,
+        "archived": false,
+        "clicked": false,
+        "author": "ferris",
+        "score": 100.0,
+        "over_18": false,
+        "hidden": false,
+        "num_comments": 10,
+        "thumbnail": "",
+        "subreddit_id": "t5_2s7lj",
+        "hide_score": false,
+        "edited": false,
+        "downs": 0.0,
+        "ups": 100.0,
+        "upvote_ratio": 0.9,
+        "saved": false,
+        "stickied": false,
+        "is_self": false,
+        "permalink": "/r/rust/comments/abc123/a_post/",
+        "locked": false,
+        "name": "t3_abc123",
+        "created": 1_700_000_000.0,
+        "created_utc": 1_700_000_000.0,
+        "quarantine": false,
+        "title": "A post",
+        "visited": false,
+    });
+    for (key, val) in fields.as_object().expect("fields must be an object") {
+        data[key] = val.clone();
+    }
+    serde_json::from_value(serde_json::json!({"kind": "t3", "data": data})).expect("bad test post")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn views_round_trip() {
+        for view in RedReq::groups().into_iter().flatten() {
+            assert_eq!(RedReq::from_str(&view.to_string()), Ok(view.clone()));
+            assert_eq!(RedReq::from_alias(&view.to_string()), Some(view));
+        }
+    }
+
+    #[test]
+    fn views_format() {
+        // stored in the subscriptions, and in the buttons
+        let views = [
+            (RedReq::Best, "Best", r#""Best""#),
+            (RedReq::Rise, "Rise", r#""Rise""#),
+            (RedReq::Top(Period::W), "TopW", r#"{"Top":"W"}"#),
+            (
+                RedReq::Controversial(Period::D),
+                "ContD",
+                r#"{"Controversial":"D"}"#,
+            ),
+            (RedReq::Top(Period::H), "TopH", r#"{"Top":"H"}"#),
+        ];
+        for (view, text, json) in views {
+            assert_eq!(view.to_string(), text);
+            assert_eq!(RedReq::from_str(text), Ok(view.clone()));
+            assert_eq!(serde_json::to_string(&view).unwrap(), json);
+            assert_eq!(serde_json::from_str::<RedReq>(json).unwrap(), view);
+        }
+        for bad in ["", "hot", "Top", "TopX", "ContW ", "Controversial"] {
+            assert!(RedReq::from_str(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn view_aliases() {
+        assert_eq!(RedReq::from_alias("rising"), Some(RedReq::Rise));
+        assert_eq!(RedReq::from_alias("HOT"), Some(RedReq::Hot));
+        assert_eq!(RedReq::from_alias("top"), Some(RedReq::Top(Period::D)));
+        assert_eq!(
+            RedReq::from_alias("controversial"),
+            Some(RedReq::Controversial(Period::D))
+        );
+        assert_eq!(RedReq::from_alias("newest"), None);
+        assert_eq!(Period::from_alias("week"), Some(Period::W));
+        assert_eq!(Period::from_alias("W"), Some(Period::W));
+        assert_eq!(Period::from_alias("all"), Some(Period::A));
+        assert_eq!(Period::from_alias("today"), Some(Period::D));
+        assert_eq!(Period::from_alias("fortnight"), None);
+    }
+
+    fn sniffed(head: &[u8], ctype: &str) -> Option<(&'static str, &'static str)> {
+        let (typ, ext) = sniff(head, ctype)?;
+        let kind = match typ {
+            FSFile::Image { f: _ } => "image",
+            FSFile::Animation { f: _ } => "animation",
+            FSFile::Video { f: _ } => "video",
+            FSFile::Audio { f: _ } => "audio",
+            FSFile::Document { f: _ } => "document",
+        };
+        Some((kind, ext))
+    }
+
+    #[test]
+    fn sniffs_magic_bytes() {
+        let cases: [(&[u8], _); 11] = [
+            (b"\xFF\xD8\xFF\xE0\x00\x10JFIF", ("image", "jpg")),
+            (b"\x89PNG\r\n\x1A\n", ("image", "png")),
+            (b"GIF89a\x01\x00", ("animation", "gif")),
+            (b"RIFF\x24\x00\x00\x00WEBPVP8 ", ("image", "webp")),
+            (b"\x00\x00\x00\x18ftypmp42", ("video", "mp4")),
+            (b"\x00\x00\x00\x20ftypisom", ("video", "mp4")),
+            (b"\x00\x00\x00\x14ftypqt  ", ("video", "mov")),
+            (b"\x00\x00\x00\x20ftypM4A ", ("audio", "m4a")),
+            (b"\x1A\x45\xDF\xA3\x01", ("video", "webm")),
+            (b"ID3\x04\x00", ("audio", "mp3")),
+            (b"%PDF-1.7", ("document", "pdf")),
+        ];
+        for (head, kind) in cases {
+            // the content wins over the declared type
+            assert_eq!(sniffed(head, "text/html"), Some(kind), "{:?}", head);
+        }
+    }
+
+    #[test]
+    fn sniffs_pages() {
+        let html = b"<!DOCTYPE html><html><head>";
+        assert_eq!(sniffed(html, "text/html; charset=utf-8"), None);
+        assert_eq!(sniffed(html, ""), None);
+        assert_eq!(sniffed(b"", ""), None);
+        // a RIFF file which is not an image
+        assert_eq!(sniffed(b"RIFF\x24\x00\x00\x00WAVEfmt ", ""), None);
+        // unknown content, trust the declared type
+        assert_eq!(sniffed(b"\x00\x01", "image/png"), Some(("image", "png")));
+    }
+
+    #[test]
+    fn canonical_urls() {
+        let same = [
+            "https://www.example.com/a/b/?x=1#top",
+            "http://example.com/a/b?x=1",
+            "https://example.com/a/b/?x=1",
+        ];
+        for url in same {
+            assert_eq!(canonical_url(url).as_deref(), Some("example.com/a/b?x=1"));
+        }
+        assert_ne!(
+            canonical_url("https://example.com/a/b?x=2"),
+            canonical_url("https://example.com/a/b?x=1")
+        );
+        let image = Some("i.redd.it/abc123.jpg".to_string());
+        assert_eq!(canonical_url("https://i.redd.it/abc123.jpg"), image);
+        let preview = "https://preview.redd.it/abc123.jpg?width=640&format=pjpg&auto=webp&s=f00";
+        assert_eq!(canonical_url(preview), image);
+        assert_eq!(canonical_url("not a url"), None);
+    }
+
+    #[test]
+    fn crossposts_are_seen() {
+        let original = test_post(serde_json::json!({
+            "name": "t3_orig",
+            "url": "https://i.redd.it/abc123.jpg",
+        }));
+        let crosspost = test_post(serde_json::json!({
+            "name": "t3_cross",
+            "subreddit": "pics",
+            "url": "https://www.reddit.com/r/rust/comments/orig/a_post/",
+            "crosspost_parent": "t3_orig",
+            "crosspost_parent_list": [{}],
+        }));
+        let reposted = test_post(serde_json::json!({
+            "name": "t3_repost",
+            "url": "https://preview.redd.it/abc123.jpg?width=960&s=abc",
+        }));
+        let keys = seen_keys(&original);
+        assert_eq!(keys, ["t3_orig", "i.redd.it/abc123.jpg"]);
+        assert!(seen_keys(&crosspost).iter().any(|k| keys.contains(k)));
+        assert!(seen_keys(&reposted).iter().any(|k| keys.contains(k)));
+        // self posts are only known by their names
+        let own = test_post(serde_json::json!({"is_self": true, "url": "https://example.com/"}));
+        assert_eq!(seen_keys(&own), ["t3_abc123"]);
+    }
+
+    #[test]
+    fn splits_albums() {
+        assert!(album_sizes(0, 10).is_empty());
+        assert_eq!(album_sizes(1, 10), [1]);
+        assert_eq!(album_sizes(10, 10), [10]);
+        assert_eq!(album_sizes(11, 10), [6, 5]);
+        assert_eq!(album_sizes(20, 10), [10, 10]);
+        assert_eq!(album_sizes(41, 10), [9, 8, 8, 8, 8]);
+        for len in 1..=100 {
+            let sizes = album_sizes(len, 10);
+            assert_eq!(sizes.iter().sum::<usize>(), len);
+            assert!(sizes.iter().all(|&s| s <= 10));
+            assert!(sizes[0] - sizes[sizes.len() - 1] <= 1, "{:?}", sizes);
+            assert!(len == 1 || sizes.iter().all(|&s| s >= 2), "{:?}", sizes);
+        }
+    }
+
+    fn gallery() -> serde_json::Value {
+        serde_json::json!({
+            "gallery_data": {"items": [{"media_id": "b"}, {"media_id": "a"}, {"media_id": "c"}]},
+            "media_metadata": {
+                "a": {"status": "valid", "e": "Image", "m": "image/png",
+                      "s": {"u": "https://i.redd.it/a.png"}},
+                "b": {"status": "valid", "e": "AnimatedImage",
+                      "s": {"gif": "https://i.redd.it/b.gif", "mp4": "https://i.redd.it/b.mp4"}},
+                "c": {"status": "failed"},
+            },
+        })
+    }
+
+    #[test]
+    fn gallery_in_order() {
+        let post = test_post(gallery());
+        let items: Vec<_> = gallery_items(&post.data)
+            .into_iter()
+            .map(|(url, _, ext)| (url, ext))
+            .collect();
+        assert_eq!(
+            items,
+            [
+                ("https://i.redd.it/b.mp4".to_string(), "mp4"),
+                ("https://i.redd.it/a.png".to_string(), "png"),
+            ]
+        );
+    }
+
+    #[test]
+    fn crossposted_gallery() {
+        let post = test_post(serde_json::json!({
+            "url": "https://www.reddit.com/gallery/orig",
+            "crosspost_parent": "t3_orig",
+            "crosspost_parent_list": [gallery()],
+        }));
+        assert_eq!(gallery_items(&post.data).len(), 2);
+        assert!(gallery_items(&test_post(serde_json::json!({})).data).is_empty());
+    }
+
+    #[test]
+    fn http_errors() {
+        let error =
+            |code, reason, user| error_of(StatusCode::from_u16(code).unwrap(), reason, user);
+        let private = Some("private");
+        assert!(matches!(error(404, None, false), RedditError::NotFound));
+        assert!(matches!(error(403, private, false), RedditError::Private));
+        assert!(matches!(
+            error(404, Some("banned"), false),
+            RedditError::Banned
+        ));
+        assert!(matches!(error(429, None, false), RedditError::RateLimited));
+        assert!(matches!(error(500, None, false), RedditError::Network(_)));
+        // suspended or deleted accounts
+        assert!(matches!(error(404, None, true), RedditError::UserNotFound));
+        assert!(matches!(
+            error(403, private, true),
+            RedditError::UserNotFound
+        ));
+        assert!(matches!(error(429, None, true), RedditError::RateLimited));
+    }
+}
@@ -0,0 +1,251 @@
+use crate::callback;
+use crate::persist;
+use crate::reddit;
+use crate::telegram;
+use crate::topic::InTopic;
+use crate::MyState;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use teloxide::prelude::*;
+
+// When a subscription has to be delivered. Times are in UTC.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Schedule {
+    Daily { hour: u32, minute: u32 },
+    Every { hours: u32 },
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Schedule::Daily { hour, minute } => write!(f, "daily {:02}:{:02}", hour, minute),
+            Schedule::Every { hours } => write!(f, "every {}h", hours),
+        }
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    // Accepts "daily HH:MM" and "every Nh" (the keywords are optional)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_lowercase();
+        let err = || format!("Invalid schedule: {}", s);
+        let (kind, val) = match s.split_once(char::is_whitespace) {
+            Some((k, v)) => (k, v.trim()),
+            None if s.contains(':') => ("daily", s.as_str()),
+            None => ("every", s.as_str()),
+        };
+        match kind {
+            "daily" => {
+                let (h, m) = val.split_once(':').ok_or_else(err)?;
+                let hour: u32 = h.parse().map_err(|_| err())?;
+                let minute: u32 = m.parse().map_err(|_| err())?;
+                if hour > 23 || minute > 59 {
+                    return Err(err());
+                }
+                Ok(Schedule::Daily { hour, minute })
+            }
+            "every" => {
+                let hours = val.trim_end_matches('h').trim();
+                let hours: u32 = hours.parse().map_err(|_| err())?;
+                if hours == 0 {
+                    return Err(err());
+                }
+                Ok(Schedule::Every { hours })
+            }
+            _ => Err(err()),
+        }
+    }
+}
+
+impl Schedule {
+    // Unix time of the first delivery strictly after `now`
+    pub fn next_run(&self, now: i64) -> i64 {
+        match self {
+            Schedule::Every { hours } => now + 3600 * *hours as i64,
+            Schedule::Daily { hour, minute } => {
+                let day = 86400;
+                let at = (*hour as i64) * 3600 + (*minute as i64) * 60;
+                let midnight = now - now.rem_euclid(day);
+                if midnight + at > now {
+                    midnight + at
+                } else {
+                    midnight + day + at
+                }
+            }
+        }
+    }
+}
+
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System clock is before 1970")
+        .as_secs() as i64
+}
+
+// Forget old seen posts, button actions and cached media
+async fn prune(my_state: &MyState) {
+    // forget posts seen more than a month ago
+    if let Err(e) = persist::prune_seen(&my_state.db, now() - 30 * 86400).await {
+        log::error!("Cannot prune seen posts: {}", e);
+    }
+    let older_than = now() - callback::EXPIRY_DAYS * 86400;
+    if let Err(e) = persist::prune_callbacks(&my_state.db, older_than).await {
+        log::error!("Cannot prune button actions: {}", e);
+    }
+    let cache = &my_state.my_conf.media_cache;
+    if cache.enabled {
+        let older_than = now() - cache.expiry_days as i64 * 86400;
+        if let Err(e) = persist::prune_media_cache(&my_state.db, older_than).await {
+            log::error!("Cannot prune media cache: {}", e);
+        }
+    }
+}
+
+// Background task delivering the due subscriptions
+pub async fn run(bot: Bot, my_state: Arc<MyState>) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(30));
+    // the DB is pruned at startup, and then hourly
+    let mut next_prune = 0;
+    loop {
+        ticker.tick().await;
+        if now() >= next_prune {
+            prune(&my_state).await;
+            next_prune = now() + 3600;
+        }
+        let due = match persist::fetch_due_subscriptions(&my_state.db, now()).await {
+            Ok(due) => due,
+            Err(e) => {
+                log::error!("Cannot fetch subscriptions: {}", e);
+                continue;
+            }
+        };
+        for sub in due {
+            // schedule next delivery first, so that a failing one is not retried in a loop
+            let next = match Schedule::from_str(&sub.schedule) {
+                Ok(sched) => sched.next_run(now()),
+                Err(e) => {
+                    log::error!("Subscription {}: {}", sub.id, e);
+                    i64::MAX
+                }
+            };
+            if let Err(e) = persist::set_next_run(&my_state.db, sub.id, next).await {
+                log::error!("Cannot update subscription {}: {}", sub.id, e);
+                continue;
+            }
+            // the chat might have been removed from the whitelist since
+            if !telegram::allowed(&sub.chat_id, &my_state.my_conf.id_whitelist) {
+                log::info!("Skipping subscription {} of a chat not allowed", sub.id);
+                continue;
+            }
+            let dest = sub.dest;
+            log::info!("Delivering subscription {} to {:?}", sub.id, dest);
+            let mut rcmd = sub.rcmd;
+            let header = format!(
+                "Your {} digest: {} {} posts from {}",
+                sub.schedule, rcmd.tot, rcmd.view, rcmd.subreddit
+            );
+            let sent = bot
+                .send_message(dest.chat_id, header)
+                .in_topic(dest.topic)
+                .await;
+            let res = match sent {
+                Ok(_) => {
+                    let sub_id = Some(sub.id);
+                    let chat_id = sub.chat_id;
+                    reddit::send_posts(bot.clone(), chat_id, dest, &mut rcmd, &my_state, sub_id)
+                        .await
+                }
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = res {
+                log::error!("Cannot deliver subscription {}: {}", sub.id, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // some midnight UTC
+    const DAY: i64 = 19_000 * 86400;
+
+    fn parse(s: &str) -> Result<Schedule, String> {
+        Schedule::from_str(s)
+    }
+
+    #[test]
+    fn parses_every() {
+        let six = Schedule::Every { hours: 6 };
+        assert_eq!(parse("every 6h"), Ok(six.clone()));
+        assert_eq!(parse("every 6 h"), Ok(six.clone()));
+        assert_eq!(parse("Every 6"), Ok(six.clone()));
+        assert_eq!(parse("6h"), Ok(six));
+        assert!(parse("every 0h").is_err());
+        assert!(parse("0h").is_err());
+        assert!(parse("every h").is_err());
+        assert!(parse("every -1h").is_err());
+    }
+
+    #[test]
+    fn parses_daily() {
+        let at = |hour, minute| Ok(Schedule::Daily { hour, minute });
+        assert_eq!(parse("daily 08:30"), at(8, 30));
+        assert_eq!(parse("08:30"), at(8, 30));
+        assert_eq!(parse("daily 23:59"), at(23, 59));
+        assert_eq!(parse("daily 0:00"), at(0, 0));
+        assert!(parse("daily 24:00").is_err());
+        assert!(parse("daily 12:60").is_err());
+        assert!(parse("daily 12").is_err());
+        assert!(parse("weekly 12:00").is_err());
+        assert!(parse("").is_err());
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for s in ["daily 08:30", "daily 23:59", "every 1h", "every 12h"] {
+            assert_eq!(parse(s).unwrap().to_string(), s);
+        }
+    }
+
+    #[test]
+    fn next_run_every() {
+        let sched = Schedule::Every { hours: 3 };
+        assert_eq!(sched.next_run(DAY + 100), DAY + 100 + 3 * 3600);
+    }
+
+    #[test]
+    fn next_run_daily() {
+        let sched = Schedule::Daily {
+            hour: 8,
+            minute: 30,
+        };
+        let at = 8 * 3600 + 30 * 60;
+        // later today
+        assert_eq!(sched.next_run(DAY), DAY + at);
+        assert_eq!(sched.next_run(DAY + at - 1), DAY + at);
+        // strictly after now, so tomorrow
+        assert_eq!(sched.next_run(DAY + at), DAY + 86400 + at);
+        assert_eq!(sched.next_run(DAY + 10 * 3600), DAY + 86400 + at);
+    }
+
+    #[test]
+    fn next_run_wraps_at_midnight() {
+        let midnight = Schedule::Daily { hour: 0, minute: 0 };
+        assert_eq!(midnight.next_run(DAY), DAY + 86400);
+        assert_eq!(midnight.next_run(DAY - 1), DAY);
+        let late = Schedule::Daily {
+            hour: 23,
+            minute: 59,
+        };
+        let at = 23 * 3600 + 59 * 60;
+        assert_eq!(late.next_run(DAY), DAY + at);
+        assert_eq!(late.next_run(DAY + at + 30), DAY + 86400 + at);
+    }
+}
@@ -1,59 +1,67 @@
-use crate::persist;
+use crate::callback::{self, Action};
+use crate::caption;
+use crate::filters::{self, Filters};
+use crate::persist::{self, DialogueStorage};
 use crate::reddit;
-use crate::reddit::{RedReq, RedditCmd};
-use crate::{HashSet, MyState, SubredditsCats, UrlMatches};
+use crate::reddit::{Period, RedReq, RedditCmd, RedditError, Search, SearchSort};
+use crate::scheduler::{self, Schedule};
+use crate::topic::{self, Dest, InTopic, Topic};
+use crate::watcher;
+use crate::{HashSet, MyState, SubredditsCats, SubsFile};
+use serde::{Deserialize, Serialize};
 use std::str::FromStr;
 use std::sync::Arc;
 use strum::IntoEnumIterator;
 use teloxide::{
-    dispatching::{dialogue, dialogue::InMemStorage, UpdateHandler},
+    dispatching::{dialogue, UpdateHandler},
     net::Download,
     payloads,
     prelude::*,
     requests::JsonRequest,
-    types::{InlineKeyboardButton, InlineKeyboardMarkup, InputFile, MessageId, ParseMode},
+    types::{
+        InlineKeyboardButton, InlineKeyboardMarkup, InputFile, MessageId, ParseMode, Recipient,
+        User,
+    },
     utils::command::BotCommands,
 };
 use tokio::fs;
 use uuid::Uuid;
 
-type MyDialogue = Dialogue<State, InMemStorage<State>>;
+type MyDialogue = Dialogue<State, DialogueStorage>;
 pub type HandlerResult = Result<(), Box<dyn std::error::Error + Send + Sync>>;
 
-#[derive(Default, Clone)]
+#[derive(Default, Clone, Serialize, Deserialize)]
 pub enum State {
     #[default]
     Bouncer,
-    Start {
-        my_state: Arc<MyState>,
-    },
+    Start,
     SelectSubreddit {
-        my_state: Arc<MyState>,
-        prev: Option<MessageId>,
-    },
-    SelectView {
-        my_state: Arc<MyState>,
-        rcmd: RedditCmd,
-        prev: Option<MessageId>,
-    },
-    SelectTot {
-        my_state: Arc<MyState>,
-        rcmd: RedditCmd,
-        prev: Option<MessageId>,
-    },
-    IssueCmd {
-        my_state: Arc<MyState>,
-        rcmd: RedditCmd,
-        prev: Option<MessageId>,
-    },
-    NextPage {
-        my_state: Arc<MyState>,
-        rcmd: RedditCmd,
         prev: Option<MessageId>,
     },
-    AcceptJSON {
-        my_state: Arc<MyState>,
-    },
+    AcceptJSON,
+}
+
+// Button merging all the subreddits of a category
+const ALL_IN_CATEGORY: &str = "All in this category";
+
+// Answer to the members of a group who are not allowed to change its configuration
+const ADMINS_ONLY: &str = "Sorry, only the admins of this group can change this.";
+
+// Per-chat settings: key, description and possible values (the first is the default,
+// unless set in the configuration file)
+const SETTINGS: [(&str, &str, &[&str]); 4] = [
+    ("skip_seen", "Skip already seen posts", &["on", "off"]),
+    ("large_media", "Large media", &["compressed", "original"]),
+    ("nsfw", "NSFW and spoiler posts", &["hide", "blur", "show"]),
+    ("admin_only", "Changes by admins only", &["off", "on"]),
+];
+
+// Value of a setting not chosen by the user
+fn default_setting(my_state: &MyState, key: &str, values: &[&str]) -> String {
+    match key {
+        "nsfw" => my_state.my_conf.nsfw_policy.to_string(),
+        _ => values[0].to_string(),
+    }
 }
 
 #[derive(BotCommands, Clone)]
@@ -72,6 +80,32 @@ pub enum Command {
     SendSubs,
     #[command(description = "Delete your customized JSON list of subreddits.")]
     DelSubs,
+    #[command(description = "Schedule a digest: <subreddit> <view> <tot> <schedule>.")]
+    Subscribe(String),
+    #[command(description = "List and remove your scheduled digests.")]
+    Subscriptions,
+    #[command(description = "Change your preferences.")]
+    Settings,
+    #[command(description = "Customize the captions of posts: <template>, or default.")]
+    Caption(String),
+    #[command(description = "Search posts: <query> [in r/<subreddit>] [sort] [period].")]
+    Search(String),
+    #[command(description = "Show the posts submitted by a user: <name>.")]
+    User(String),
+    #[command(description = "Show hot posts: <subreddit> [tot].")]
+    Hot(String),
+    #[command(description = "Show top posts: <subreddit> [period] [tot].")]
+    Top(String),
+    #[command(description = "Show rising posts: <subreddit> [tot].")]
+    Rising(String),
+    #[command(description = "Show posts: <subreddit> [view] [period] [tot].")]
+    R(String),
+    #[command(description = "Filter posts: [#<subscription>] <rule> [values], or reset.")]
+    Filters(String),
+    #[command(description = "Get notified of new posts: <regex> in <subreddits>.")]
+    Watch(String),
+    #[command(description = "Stop a notification: <number>.")]
+    Unwatch(String),
 }
 
 pub fn schema(
@@ -89,8 +123,43 @@ pub fn schema(
     let run_send_json = move |bot: Bot, dialogue: MyDialogue, msg: Message| {
         send_json(bot, dialogue, msg, tmp_state.clone())
     };
+    let tmp_state = my_state.clone();
     let run_del_json = move |bot: Bot, dialogue: MyDialogue, msg: Message| {
-        del_json(bot, dialogue, msg, my_state.clone())
+        del_json(bot, dialogue, msg, tmp_state.clone())
+    };
+    let tmp_state = my_state.clone();
+    let run_subscribe =
+        move |bot: Bot, msg: Message, args: String| subscribe(bot, msg, args, tmp_state.clone());
+    let tmp_state = my_state.clone();
+    let run_subscriptions = move |bot: Bot, dialogue: MyDialogue, msg: Message| {
+        subscriptions(bot, dialogue, msg, tmp_state.clone())
+    };
+    let tmp_state = my_state.clone();
+    let run_search = move |bot: Bot, dialogue: MyDialogue, msg: Message, args: String| {
+        search(bot, dialogue, msg, args, tmp_state.clone())
+    };
+    let tmp_state = my_state.clone();
+    let run_user = move |bot: Bot, dialogue: MyDialogue, msg: Message, name: String| {
+        user(bot, dialogue, msg, name, tmp_state.clone())
+    };
+    let tmp_state = my_state.clone();
+    let run_shortcut = move |bot: Bot, dialogue: MyDialogue, msg: Message, cmd: Command| {
+        shortcut(bot, dialogue, msg, cmd, tmp_state.clone())
+    };
+    let tmp_state = my_state.clone();
+    let run_filters =
+        move |bot: Bot, msg: Message, args: String| filters(bot, msg, args, tmp_state.clone());
+    let tmp_state = my_state.clone();
+    let run_watch =
+        move |bot: Bot, msg: Message, args: String| watch(bot, msg, args, tmp_state.clone());
+    let tmp_state = my_state.clone();
+    let run_unwatch =
+        move |bot: Bot, msg: Message, args: String| unwatch(bot, msg, args, tmp_state.clone());
+    let tmp_state = my_state.clone();
+    let run_caption =
+        move |bot: Bot, msg: Message, args: String| caption(bot, msg, args, tmp_state.clone());
+    let run_settings = move |bot: Bot, dialogue: MyDialogue, msg: Message| {
+        settings(bot, dialogue, msg, my_state.clone())
     };
 
     let command_handler = teloxide::filter_command::<Command, _>()
@@ -98,63 +167,58 @@ pub fn schema(
         .branch(case![Command::GetSubs].endpoint(run_get_json))
         .branch(case![Command::SendSubs].endpoint(run_send_json))
         .branch(case![Command::DelSubs].endpoint(run_del_json))
+        .branch(case![Command::Subscribe(args)].endpoint(run_subscribe))
+        .branch(case![Command::Subscriptions].endpoint(run_subscriptions))
+        .branch(case![Command::Settings].endpoint(run_settings))
+        .branch(case![Command::Caption(args)].endpoint(run_caption))
+        .branch(case![Command::Search(args)].endpoint(run_search))
+        .branch(case![Command::User(name)].endpoint(run_user))
+        .branch(case![Command::Hot(args)].endpoint(run_shortcut.clone()))
+        .branch(case![Command::Top(args)].endpoint(run_shortcut.clone()))
+        .branch(case![Command::Rising(args)].endpoint(run_shortcut.clone()))
+        .branch(case![Command::R(args)].endpoint(run_shortcut))
+        .branch(case![Command::Filters(args)].endpoint(run_filters))
+        .branch(case![Command::Watch(args)].endpoint(run_watch))
+        .branch(case![Command::Unwatch(args)].endpoint(run_unwatch))
         .branch(case![Command::Start].endpoint(run_bouncer));
 
     let message_handler = Update::filter_message()
         .branch(command_handler)
-        .branch(case![State::AcceptJSON { my_state }].endpoint(accept_json))
-        .branch(case![State::SelectSubreddit { my_state, prev }].endpoint(sub_from_msg))
+        .branch(case![State::AcceptJSON].endpoint(accept_json))
+        .branch(case![State::SelectSubreddit { prev }].endpoint(sub_from_msg))
         .branch(dptree::endpoint(invalid_state));
 
+    // buttons carry their own action, and can be pressed in any state
+    let is_action = |q: CallbackQuery| q.data.is_some_and(|d| callback::is_action(&d));
     let callback_query_handler = Update::filter_callback_query()
-        .branch(case![State::SelectSubreddit { my_state, prev }].endpoint(select_subreddit))
-        .branch(
-            case![State::SelectView {
-                my_state,
-                rcmd,
-                prev
-            }]
-            .endpoint(select_view),
-        )
-        .branch(
-            case![State::SelectTot {
-                my_state,
-                rcmd,
-                prev
-            }]
-            .endpoint(select_tot),
-        )
-        .branch(
-            case![State::IssueCmd {
-                my_state,
-                rcmd,
-                prev
-            }]
-            .endpoint(issue_cmd),
-        )
-        .branch(
-            case![State::NextPage {
-                my_state,
-                rcmd,
-                prev
-            }]
-            .endpoint(next_page),
-        );
+        .branch(dptree::filter(is_action).endpoint(run_action))
+        .branch(dptree::endpoint(expired_button));
+
+    // inline queries come from no chat, and so have no dialogue
+    let inline_query_handler = Update::filter_inline_query().endpoint(inline_query);
 
-    dialogue::enter::<Update, InMemStorage<State>, State, _>()
+    let dialogue_handler = dialogue::enter::<Update, DialogueStorage, State, _>()
         .branch(message_handler)
-        .branch(callback_query_handler)
+        .branch(callback_query_handler);
+
+    dptree::entry()
+        .branch(inline_query_handler)
+        .branch(dialogue_handler)
 }
 
 async fn help(bot: Bot, msg: Message) -> HandlerResult {
-    bot.send_message(msg.chat.id, Command::descriptions().to_string())
-        .await?;
+    reply(&bot, &msg, Command::descriptions().to_string()).await?;
     Ok(())
 }
 
 async fn invalid_state(bot: Bot, msg: Message) -> HandlerResult {
-    bot.send_message(
-        msg.chat.id,
+    // in groups, the bot sees the replies to its own messages: ignore the chatter
+    if !msg.chat.is_private() {
+        return Ok(());
+    }
+    reply(
+        &bot,
+        &msg,
         "Unable to handle the message. Type /help to see the usage.",
     )
     .await?;
@@ -171,16 +235,20 @@ async fn send_json(
     let chat_id = msg.chat.id;
     let wl = &my_state.my_conf.id_whitelist;
     if !allowed(&chat_id, wl) {
-        bot.send_message(chat_id, "Sorry dude, you're not in the whitelist.")
-            .await?;
+        reply(&bot, &msg, "Sorry dude, you're not in the whitelist.").await?;
         return Ok(());
     }
-    bot.send_message(
-        chat_id,
+    if !may_configure(&bot, &my_state, chat_id, msg.from()).await? {
+        reply(&bot, &msg, ADMINS_ONLY).await?;
+        return Ok(());
+    }
+    reply(
+        &bot,
+        &msg,
         "Ok, please send the customized JSON file (as an attachment).",
     )
     .await?;
-    dialogue.update(State::AcceptJSON { my_state }).await?;
+    dialogue.update(State::AcceptJSON).await?;
     Ok(())
 }
 
@@ -191,12 +259,17 @@ async fn accept_json(
     my_state: Arc<MyState>,
 ) -> HandlerResult {
     let chat_id = msg.chat.id;
+    if !may_configure(&bot, &my_state, chat_id, msg.from()).await? {
+        reply(&bot, &msg, ADMINS_ONLY).await?;
+        return Ok(());
+    }
     let doc = msg.document();
     let max_size = 20000;
     match doc {
         None => {
-            bot.send_message(
-                chat_id,
+            reply(
+                &bot,
+                &msg,
                 "JSON file is missing, please send it as an attachment.",
             )
             .await?;
@@ -211,34 +284,37 @@ async fn accept_json(
             }
             let subs_txt = fs::read_to_string(&tmpfile).await?;
             std::fs::remove_file(tmpfile)?;
-            let new_subs = serde_json::from_str::<SubredditsCats>(&subs_txt);
+            let new_subs = SubsFile::parse(&subs_txt)
+                .map_err(|e| e.to_string())
+                .and_then(|subs| match subs.filters.compile() {
+                    Ok(_) => Ok(subs),
+                    Err(e) => Err(format!("invalid filters ({})", e)),
+                });
             match new_subs {
                 Err(e) => {
-                    bot.send_message(
-                        chat_id,
+                    reply(
+                        &bot,
+                        &msg,
                         format!("Error while parsing your JSON file: {}.", e),
                     )
                     .await?;
                 }
                 Ok(subs) => {
-                    persist::insert_pref(&my_state.db, chat_id, &subs)
+                    persist::insert_pref(&my_state.db, chat_id, &subs.subreddits)
                         .await
                         .expect("Error: cannot insert values in DB");
-                    bot.send_message(chat_id, "Your subreddits have been succesfully updated.")
-                        .await?;
+                    persist::set_filters(&my_state.db, chat_id, None, &subs.filters).await?;
+                    reply(&bot, &msg, "Your subreddits have been succesfully updated.").await?;
                     // restart menu
-                    dialogue
-                        .update(State::Start {
-                            my_state: my_state.clone(),
-                        })
-                        .await?;
-                    select_category(bot, dialogue, my_state).await?;
+                    dialogue.update(State::Start).await?;
+                    select_category(bot, dialogue, my_state, topic::of(&msg)).await?;
                 }
             }
         }
         _ => {
-            bot.send_message(
-                chat_id,
+            reply(
+                &bot,
+                &msg,
                 format!(
                     "JSON file is too big, must be smaller than {} bytes. Please send it again.",
                     max_size
@@ -256,13 +332,23 @@ async fn del_json(
     msg: Message,
     my_state: Arc<MyState>,
 ) -> HandlerResult {
+    // whitelist check
     let chat_id = msg.chat.id;
+    let wl = &my_state.my_conf.id_whitelist;
+    if !allowed(&chat_id, wl) {
+        reply(&bot, &msg, "Sorry dude, you're not in the whitelist.").await?;
+        return Ok(());
+    }
+    if !may_configure(&bot, &my_state, chat_id, msg.from()).await? {
+        reply(&bot, &msg, ADMINS_ONLY).await?;
+        return Ok(());
+    }
     let num = persist::del_prefs(&my_state.db, chat_id).await?;
     let txt = match num {
         0 => "There's no saved list to delete.",
         _ => "Your customized list of subreddits has been deleted.",
     };
-    bot.send_message(chat_id, txt).await?;
+    reply(&bot, &msg, txt).await?;
     bouncer(bot, dialogue, msg, my_state).await
 }
 
@@ -271,26 +357,65 @@ async fn get_json(bot: Bot, msg: Message, my_state: Arc<MyState>) -> HandlerResu
     let chat_id = msg.chat.id;
     let wl = &my_state.my_conf.id_whitelist;
     if !allowed(&chat_id, wl) {
-        bot.send_message(chat_id, "Sorry dude, you're not in the whitelist.")
-            .await?;
+        reply(&bot, &msg, "Sorry dude, you're not in the whitelist.").await?;
         return Ok(());
     }
 
-    let subs = get_catsubs(&my_state, chat_id).await;
+    let subs = SubsFile {
+        filters: persist::get_filters(&my_state.db, chat_id, None).await?,
+        subreddits: get_catsubs(&my_state, chat_id).await,
+    };
     let mut subs = serde_json::to_string_pretty(&subs).unwrap();
     subs.push('\n'); // add EOL
     bot.send_document(
         chat_id,
         InputFile::memory(subs).file_name("my_subreddits.json"),
     )
+    .in_topic(topic::of(&msg))
     .await?;
     Ok(())
 }
 
-fn allowed(chat_id: &ChatId, whitelist: &HashSet<ChatId>) -> bool {
+pub fn allowed(chat_id: &ChatId, whitelist: &HashSet<ChatId>) -> bool {
     whitelist.is_empty() | whitelist.contains(chat_id)
 }
 
+// Answer a message in its forum topic, and in groups as a reply to it
+fn reply(bot: &Bot, msg: &Message, text: impl Into<String>) -> JsonRequest<payloads::SendMessage> {
+    let req = bot.send_message(msg.chat.id, text).in_topic(topic::of(msg));
+    if msg.chat.is_private() {
+        return req;
+    }
+    req.reply_to_message_id(msg.id)
+        .allow_sending_without_reply(true)
+}
+
+// Whether the user can change the settings and subscriptions of the chat:
+// in groups with the admin_only setting, only the admins can
+async fn may_configure(
+    bot: &Bot,
+    my_state: &MyState,
+    chat_id: ChatId,
+    user: Option<&User>,
+) -> anyhow::Result<bool> {
+    if chat_id.is_user() {
+        return Ok(true);
+    }
+    let admin_only = persist::get_setting(&my_state.db, chat_id, "admin_only").await?;
+    if admin_only.as_deref() != Some("on") {
+        return Ok(true);
+    }
+    match user {
+        // anonymous admins write on behalf of the group
+        Some(user) if user.is_anonymous() => Ok(true),
+        Some(user) => {
+            let member = bot.get_chat_member(chat_id, user.id).await?;
+            Ok(member.kind.is_privileged())
+        }
+        None => Ok(false),
+    }
+}
+
 async fn bouncer(
     bot: Bot,
     dialogue: MyDialogue,
@@ -302,17 +427,12 @@ async fn bouncer(
     let chat_id = msg.chat.id;
     let wl = &my_state.my_conf.id_whitelist;
     if !allowed(&chat_id, wl) {
-        bot.send_message(chat_id, "Sorry dude, you're not in the whitelist.")
-            .await?;
+        reply(&bot, &msg, "Sorry dude, you're not in the whitelist.").await?;
         return Ok(());
     }
     // set initial state
-    dialogue
-        .update(State::Start {
-            my_state: my_state.clone(),
-        })
-        .await?;
-    select_category(bot, dialogue, my_state).await
+    dialogue.update(State::Start).await?;
+    select_category(bot, dialogue, my_state, topic::of(&msg)).await
 }
 
 async fn get_categories(my_state: &MyState, chat_id: ChatId) -> Vec<String> {
@@ -345,23 +465,31 @@ async fn get_subreddits(my_state: &MyState, category: &String, chat_id: ChatId)
     subs.get(category).unwrap_or(&def_subs).to_vec()
 }
 
-async fn select_category(bot: Bot, dialogue: MyDialogue, my_state: Arc<MyState>) -> HandlerResult {
+async fn select_category(
+    bot: Bot,
+    dialogue: MyDialogue,
+    my_state: Arc<MyState>,
+    topic: Topic,
+) -> HandlerResult {
     let cats_per_row = 3;
     let chat_id = dialogue.chat_id();
     let red_cats = get_categories(&my_state, chat_id).await;
-    let red_cats = red_cats.chunks(cats_per_row).map(|r| {
-        r.iter()
-            .map(|red_cat| InlineKeyboardButton::callback(red_cat.clone(), red_cat.clone()))
-    });
+    let red_cats = red_cats
+        .chunks(cats_per_row)
+        .map(|r| {
+            r.iter()
+                .map(|red_cat| (red_cat.clone(), Action::Category(red_cat.clone())))
+                .collect()
+        })
+        .collect();
     let txt_msg = "Select a category (or type in a subreddit):".to_string();
     let sent = bot
         .send_message(dialogue.chat_id(), txt_msg)
-        .reply_markup(InlineKeyboardMarkup::new(red_cats))
+        .in_topic(topic)
+        .reply_markup(action_keyboard(&my_state, red_cats).await?)
         .await?;
     let prev = Some(sent.id);
-    dialogue
-        .update(State::SelectSubreddit { my_state, prev })
-        .await?;
+    dialogue.update(State::SelectSubreddit { prev }).await?;
     Ok(())
 }
 
@@ -373,83 +501,350 @@ async fn clean_buttons(bot: Bot, chat_id: ChatId, m_id: Option<MessageId>) -> Ha
     Ok(())
 }
 
-async fn select_subreddit(
+// Keyboard of buttons (text and action) which work whatever the dialogue state
+async fn action_keyboard(
+    my_state: &MyState,
+    rows: Vec<Vec<(String, Action)>>,
+) -> anyhow::Result<InlineKeyboardMarkup> {
+    let mut keyboard = Vec::new();
+    for row in rows {
+        let mut buttons = Vec::new();
+        for (text, action) in row {
+            let data = callback::encode(&my_state.db, &action).await?;
+            buttons.push(InlineKeyboardButton::callback(text, data));
+        }
+        keyboard.push(buttons);
+    }
+    Ok(InlineKeyboardMarkup::new(keyboard))
+}
+
+// Handle a button, from its callback data only
+async fn run_action(
     bot: Bot,
     dialogue: MyDialogue,
     q: CallbackQuery,
-    tup_state: (Arc<MyState>, Option<MessageId>),
+    my_state: Arc<MyState>,
+) -> HandlerResult {
+    let chat_id = dialogue.chat_id();
+    let data = q.data.clone().unwrap_or_default();
+    let Some(action) = callback::decode(&my_state.db, &data).await? else {
+        return expired_button(bot, dialogue, q).await;
+    };
+    // the actions skip the bouncer, check the whitelist here;
+    // the comments of the posts already sent can be read anyway
+    let comments = matches!(action, Action::Comments(..));
+    if !comments && !allowed(&chat_id, &my_state.my_conf.id_whitelist) {
+        return Ok(());
+    }
+    // older channel posts had comments buttons, which would post into the channel
+    if comments && q.message.as_ref().is_some_and(|m| m.chat.is_channel()) {
+        bot.answer_callback_query(q.id)
+            .text("Please read the comments on Reddit.")
+            .await?;
+        return Ok(());
+    }
+    let configures = matches!(
+        action,
+        Action::Subscribe(_)
+            | Action::Schedule(..)
+            | Action::Unsubscribe(_)
+            | Action::Setting(_)
+            | Action::PickCategory(_)
+            | Action::AddTo(..)
+    );
+    if configures && !may_configure(&bot, &my_state, chat_id, Some(&q.from)).await? {
+        // leave the buttons to the admins
+        bot.answer_callback_query(q.id).text(ADMINS_ONLY).await?;
+        return Ok(());
+    }
+    let topic = topic::of_query(&q);
+    // keep the buttons of the posts, which show their comments
+    if !matches!(action, Action::Comments(_, 0)) {
+        let m_id = q.message.map(|m| m.id);
+        clean_buttons(bot.clone(), chat_id, m_id).await?;
+    }
+    match action {
+        Action::Category(category) => {
+            select_subreddit(bot, dialogue, my_state, topic, category).await
+        }
+        Action::Subreddit(category, subreddit) => {
+            let tup_state = (category, subreddit);
+            select_view(bot, dialogue, my_state, topic, tup_state).await
+        }
+        Action::View(rcmd) => select_tot(bot, dialogue, my_state, topic, rcmd).await,
+        Action::Tot(rcmd) | Action::Next(rcmd) => {
+            issue_cmd(bot, dialogue, my_state, topic, rcmd).await
+        }
+        Action::Subscribe(rcmd) => select_schedule(bot, dialogue, my_state, topic, rcmd).await,
+        Action::Schedule(rcmd, sched) => {
+            save_subscription(bot, dialogue, my_state, topic, (rcmd, sched)).await
+        }
+        Action::Unsubscribe(id) => remove_subscription(bot, dialogue, my_state, topic, id).await,
+        Action::Setting(key) => change_setting(bot, dialogue, my_state, topic, key).await,
+        Action::Search(rcmd) => search_step(bot, dialogue, my_state, topic, rcmd).await,
+        Action::PickCategory(sub) => pick_category(bot, dialogue, my_state, topic, sub).await,
+        Action::AddTo(sub, category) => {
+            add_to_category(bot, dialogue, my_state, topic, (sub, category)).await
+        }
+        Action::Comments(post_id, page) => {
+            show_comments(bot, chat_id, my_state, topic, (post_id, page)).await
+        }
+        Action::Done => {
+            dialogue.update(State::Start).await?;
+            select_category(bot, dialogue, my_state, topic).await
+        }
+    }
+}
+
+// A button which cannot be handled anymore
+async fn expired_button(bot: Bot, dialogue: MyDialogue, q: CallbackQuery) -> HandlerResult {
+    let chat_id = dialogue.chat_id();
+    log::info!("{chat_id} pressed an expired button: {:?}", q.data);
+    let topic = topic::of_query(&q);
+    clean_buttons(bot.clone(), chat_id, q.message.map(|m| m.id)).await?;
+    bot.send_message(chat_id, "This button has expired, please /start again.")
+        .in_topic(topic)
+        .await?;
+    Ok(())
+}
+
+async fn select_subreddit(
+    bot: Bot,
+    dialogue: MyDialogue,
+    my_state: Arc<MyState>,
+    topic: Topic,
+    category: String,
 ) -> HandlerResult {
     let subs_per_row = 3;
-    let (my_state, m_id) = tup_state;
     let chat_id = dialogue.chat_id();
-    clean_buttons(bot.clone(), chat_id, m_id).await?;
-    let category = &q.data.unwrap_or_else(|| "News".to_string());
+    let red_subs = get_subreddits(&my_state, &category, chat_id).await;
+    let button = |sub: &str| {
+        let action = Action::Subreddit(category.clone(), sub.to_string());
+        (sub.to_string(), action)
+    };
+    let mut buttons: Vec<Vec<(String, Action)>> = red_subs
+        .chunks(subs_per_row)
+        .map(|r| r.iter().map(|red_sub| button(red_sub)).collect())
+        .collect();
+    if red_subs.len() > 1 {
+        buttons.push(vec![button(ALL_IN_CATEGORY)]);
+    }
+    let txt_msg = format!("Select a subreddit from {}:", category);
+    bot.send_message(chat_id, txt_msg)
+        .in_topic(topic)
+        .reply_markup(action_keyboard(&my_state, buttons).await?)
+        .await?;
+    dialogue.update(State::Start).await?;
+    Ok(())
+}
+
+async fn sub_from_msg(
+    bot: Bot,
+    dialogue: MyDialogue,
+    msg: Message,
+    my_state: Arc<MyState>,
+    m_id: Option<MessageId>,
+) -> HandlerResult {
+    // in groups, only the replies to the menu are subreddits, not the chatter
+    if !msg.chat.is_private() && msg.reply_to_message().map(|r| r.id) != m_id {
+        return invalid_state(bot, msg).await;
+    }
+    let topic = topic::of(&msg);
+    let sub = reddit::feed_name(msg.text().unwrap_or("All"));
     let rcmd = RedditCmd {
         view: RedReq::Hot,
-        subreddit: "".to_string(),
+        subreddit: sub.clone(),
         tot: 0,
-        category: category.to_string(),
+        category: "Custom".to_string(),
         last_seen: None,
+        search: None,
     };
-    let red_subs = get_subreddits(&my_state, category, chat_id).await;
-    let red_subs = red_subs.chunks(subs_per_row).map(|r| {
-        r.iter()
-            .map(|red_sub| InlineKeyboardButton::callback(red_sub.clone(), red_sub.clone()))
-    });
-    let txt_msg = format!("Select a subreddit from {}:", category);
-    let sent = bot
-        .send_message(chat_id, txt_msg)
-        .reply_markup(InlineKeyboardMarkup::new(red_subs))
+    // only single subreddits can be checked
+    let special = ["all", "popular"].contains(&sub.to_lowercase().as_str());
+    if special || sub.contains(['+', '/']) {
+        return select_view_core(bot, dialogue, &my_state, topic, sub, (rcmd, m_id)).await;
+    }
+    match reddit::subreddit_about(&sub).await {
+        Ok(info) => {
+            reply(&bot, &msg, info.to_string()).await?;
+            let sub = info.display_name;
+            select_view_core(bot, dialogue, &my_state, topic, sub, (rcmd, m_id)).await
+        }
+        Err(RedditError::NotFound) => {
+            suggest_subreddits(bot, dialogue, topic, (my_state, rcmd, m_id)).await
+        }
+        Err(e @ (RedditError::Private | RedditError::Banned)) => {
+            // stay here, waiting for another subreddit
+            let txt = format!("{} Please type in another subreddit.", e);
+            reply(&bot, &msg, txt).await?;
+            Ok(())
+        }
+        Err(e) => {
+            // cannot check it now, try anyway
+            log::info!("Cannot check r/{}: {}", sub, e);
+            select_view_core(bot, dialogue, &my_state, topic, sub, (rcmd, m_id)).await
+        }
+    }
+}
+
+// Offer the existing subreddits with a name similar to the typed-in one
+async fn suggest_subreddits(
+    bot: Bot,
+    dialogue: MyDialogue,
+    topic: Topic,
+    tup_state: (Arc<MyState>, RedditCmd, Option<MessageId>),
+) -> HandlerResult {
+    let (my_state, rcmd, m_id) = tup_state;
+    let chat_id = dialogue.chat_id();
+    clean_buttons(bot.clone(), chat_id, m_id).await?;
+    let similar = reddit::similar_subreddits(&rcmd.subreddit).await;
+    if similar.is_empty() {
+        let txt = format!("There is no r/{}.", rcmd.subreddit);
+        bot.send_message(chat_id, txt).in_topic(topic).await?;
+        return select_category(bot, dialogue, my_state, topic).await;
+    }
+    let buttons = similar
+        .into_iter()
+        .map(|sub| {
+            let pick = Action::Subreddit(rcmd.category.clone(), sub.clone());
+            let add = Action::PickCategory(sub.clone());
+            vec![(sub, pick), ("➕ Add to a category".to_string(), add)]
+        })
+        .collect();
+    let txt_msg = format!(
+        "There is no r/{}, did you mean one of these?",
+        rcmd.subreddit
+    );
+    bot.send_message(chat_id, txt_msg)
+        .in_topic(topic)
+        .reply_markup(action_keyboard(&my_state, buttons).await?)
         .await?;
-    let prev = Some(sent.id);
-    dialogue
-        .update(State::SelectView {
-            my_state,
-            rcmd,
-            prev,
+    dialogue.update(State::Start).await?;
+    Ok(())
+}
+
+// Ask which category to add a subreddit to
+async fn pick_category(
+    bot: Bot,
+    dialogue: MyDialogue,
+    my_state: Arc<MyState>,
+    topic: Topic,
+    sub: String,
+) -> HandlerResult {
+    let cats_per_row = 3;
+    let chat_id = dialogue.chat_id();
+    let red_cats = get_categories(&my_state, chat_id).await;
+    let red_cats = red_cats
+        .chunks(cats_per_row)
+        .map(|r| {
+            r.iter()
+                .map(|red_cat| {
+                    let action = Action::AddTo(sub.clone(), red_cat.clone());
+                    (red_cat.clone(), action)
+                })
+                .collect()
         })
+        .collect();
+    let txt_msg = format!("Add r/{} to which category?", sub);
+    bot.send_message(chat_id, txt_msg)
+        .in_topic(topic)
+        .reply_markup(action_keyboard(&my_state, red_cats).await?)
         .await?;
+    dialogue.update(State::Start).await?;
     Ok(())
 }
 
-async fn sub_from_msg(
+async fn add_to_category(
+    bot: Bot,
+    dialogue: MyDialogue,
+    my_state: Arc<MyState>,
+    topic: Topic,
+    tup_state: (String, String),
+) -> HandlerResult {
+    let (sub, category) = tup_state;
+    let chat_id = dialogue.chat_id();
+    let mut subs = get_catsubs(&my_state, chat_id).await;
+    let cat_subs = subs.entry(category.clone()).or_default();
+    if !cat_subs.contains(&sub) {
+        cat_subs.push(sub.clone());
+    }
+    persist::insert_pref(&my_state.db, chat_id, &subs).await?;
+    let txt = format!("r/{} has been added to {}.", sub, category);
+    bot.send_message(chat_id, txt).in_topic(topic).await?;
+    select_view(bot, dialogue, my_state, topic, (category, sub)).await
+}
+
+async fn user(
     bot: Bot,
     dialogue: MyDialogue,
     msg: Message,
-    tup_state: (Arc<MyState>, Option<MessageId>),
+    name: String,
+    my_state: Arc<MyState>,
 ) -> HandlerResult {
-    let (my_state, m_id) = tup_state;
-    // clean_buttons(bot.clone(), chat_id, m_id).await?;
-    let sub = msg.text().unwrap_or("All").to_string();
+    // whitelist check
+    let chat_id = msg.chat.id;
+    let wl = &my_state.my_conf.id_whitelist;
+    if !allowed(&chat_id, wl) {
+        reply(&bot, &msg, "Sorry dude, you're not in the whitelist.").await?;
+        return Ok(());
+    }
+    let name = name.trim().trim_start_matches('/');
+    let name = name.strip_prefix("u/").unwrap_or(name);
+    if name.is_empty() {
+        reply(
+            &bot,
+            &msg,
+            "Usage: /user <name> (you can also type u/<name> instead of a subreddit).",
+        )
+        .await?;
+        return Ok(());
+    }
+    let sub = reddit::feed_name(&format!("u/{}", name));
     let rcmd = RedditCmd {
         view: RedReq::Hot,
         subreddit: sub.clone(),
         tot: 0,
-        category: "Custom".to_string(),
+        category: "User".to_string(),
         last_seen: None,
+        search: None,
     };
-    select_view_core(bot, dialogue, sub, (my_state, rcmd, m_id)).await
+    let topic = topic::of(&msg);
+    select_view_core(bot, dialogue, &my_state, topic, sub, (rcmd, None)).await
 }
 
 async fn select_view(
     bot: Bot,
     dialogue: MyDialogue,
-    q: CallbackQuery,
-    tup_state: (Arc<MyState>, RedditCmd, Option<MessageId>),
+    my_state: Arc<MyState>,
+    topic: Topic,
+    tup_state: (String, String),
 ) -> HandlerResult {
-    // extract subreddit
-    let subreddit = q.data.unwrap_or_else(|| "all".to_string());
-    select_view_core(bot, dialogue, subreddit, tup_state).await
+    let (category, mut subreddit) = tup_state;
+    if subreddit == ALL_IN_CATEGORY {
+        let subs = get_subreddits(&my_state, &category, dialogue.chat_id()).await;
+        subreddit = reddit::multireddit(&subs.join("+"));
+    }
+    let rcmd = RedditCmd {
+        view: RedReq::Hot,
+        subreddit: subreddit.clone(),
+        tot: 0,
+        category,
+        last_seen: None,
+        search: None,
+    };
+    select_view_core(bot, dialogue, &my_state, topic, subreddit, (rcmd, None)).await
 }
 
 async fn select_view_core(
     bot: Bot,
     dialogue: MyDialogue,
+    my_state: &MyState,
+    topic: Topic,
     subreddit: String,
-    tup_state: (Arc<MyState>, RedditCmd, Option<MessageId>),
+    tup_state: (RedditCmd, Option<MessageId>),
 ) -> HandlerResult {
     // save subreddit
-    let (my_state, rcmd, m_id) = tup_state;
+    let (rcmd, m_id) = tup_state;
     let chat_id = dialogue.chat_id();
     clean_buttons(bot.clone(), chat_id, m_id).await?;
     let rcmd = RedditCmd {
@@ -457,157 +852,1172 @@ async fn select_view_core(
         ..rcmd
     };
     // choose view
-    let red_cmds = RedReq::iter()
-        .map(|rc| rc.to_string())
-        .map(|red_cmd| InlineKeyboardButton::callback(red_cmd.clone(), red_cmd));
-    let txt_msg = format!("Choose what to view from {}:", subreddit);
-    let sent = bot
-        .send_message(chat_id, txt_msg)
-        .reply_markup(InlineKeyboardMarkup::new([red_cmds]))
-        .await?;
-    let prev = Some(sent.id);
-    dialogue
-        .update(State::SelectTot {
-            my_state,
-            rcmd,
-            prev,
+    let groups = match subreddit.starts_with("u/") {
+        true => RedReq::user_groups(),
+        false => RedReq::groups(),
+    };
+    let red_cmds = groups
+        .into_iter()
+        .map(|r| {
+            r.into_iter()
+                .map(|view| {
+                    let rcmd = RedditCmd {
+                        view: view.clone(),
+                        ..rcmd.clone()
+                    };
+                    (view.to_string(), Action::View(rcmd))
+                })
+                .collect()
         })
+        .collect();
+    let txt_msg = format!("Choose what to view from {}:", subreddit);
+    bot.send_message(chat_id, txt_msg)
+        .in_topic(topic)
+        .reply_markup(action_keyboard(my_state, red_cmds).await?)
         .await?;
+    dialogue.update(State::Start).await?;
     Ok(())
 }
 
 async fn select_tot(
     bot: Bot,
     dialogue: MyDialogue,
-    q: CallbackQuery,
-    tup_state: (Arc<MyState>, RedditCmd, Option<MessageId>),
+    my_state: Arc<MyState>,
+    topic: Topic,
+    rcmd: RedditCmd,
+) -> HandlerResult {
+    let txt_msg = format!("How many {} posts?:", rcmd.view);
+    select_tot_core(bot, dialogue, &my_state, topic, txt_msg, rcmd).await
+}
+
+async fn select_tot_core(
+    bot: Bot,
+    dialogue: MyDialogue,
+    my_state: &MyState,
+    topic: Topic,
+    txt_msg: String,
+    rcmd: RedditCmd,
 ) -> HandlerResult {
-    let (my_state, rcmd, m_id) = tup_state;
     let chat_id = dialogue.chat_id();
-    clean_buttons(bot.clone(), chat_id, m_id).await?;
-    // save view
-    let view = &q.data.unwrap_or_else(|| "Hot".to_string());
-    let view = RedReq::from_str(view).unwrap_or(RedReq::Hot);
-    let rcmd = RedditCmd {
-        view: view.clone(),
-        ..rcmd
-    };
     // select tot
     let mut red_tots: Vec<u32> = (1..=3_u32).collect();
     red_tots.extend(vec![5, 7, 10, 20, 40]);
     let red_tots = red_tots
-        .iter()
-        .map(|rt| rt.to_string())
-        .map(|red_tot| InlineKeyboardButton::callback(red_tot.clone(), red_tot));
-    let txt_msg = format!("How many {} posts?:", view);
-    let sent = bot
-        .send_message(chat_id, txt_msg)
-        .reply_markup(InlineKeyboardMarkup::new([red_tots]))
-        .await?;
-    let prev = Some(sent.id);
-    dialogue
-        .update(State::IssueCmd {
-            my_state,
-            rcmd,
-            prev,
+        .into_iter()
+        .map(|tot| {
+            let rcmd = RedditCmd {
+                tot,
+                ..rcmd.clone()
+            };
+            (tot.to_string(), Action::Tot(rcmd))
         })
+        .collect();
+    bot.send_message(chat_id, txt_msg)
+        .in_topic(topic)
+        .reply_markup(action_keyboard(my_state, vec![red_tots]).await?)
         .await?;
+    dialogue.update(State::Start).await?;
     Ok(())
 }
 
 async fn send_page(
     bot: Bot,
     rcmd: &mut RedditCmd,
-    chat_id: ChatId,
-    url_matches: &UrlMatches,
-) -> Result<Option<MessageId>, Box<dyn std::error::Error + Send + Sync>> {
-    let summary = format!(
-        "*Shown {} {} posts from {} / {}*",
-        rcmd.tot, rcmd.view, rcmd.category, rcmd.subreddit
-    );
-    reddit::send_posts(bot.clone(), chat_id, rcmd, url_matches).await?;
-    let md = payloads::SendMessage::new(chat_id, summary);
+    dest: Dest,
+    my_state: &MyState,
+) -> HandlerResult {
+    let chat_id = dest.chat_id;
+    let sent = match reddit::send_posts(bot.clone(), chat_id, dest, rcmd, my_state, None).await {
+        Ok(sent) => sent,
+        Err(e) => {
+            let Some(e) = e.downcast_ref::<RedditError>() else {
+                return Err(e.into());
+            };
+            log::info!("{chat_id} cannot fetch {}: {e:?}", rcmd.subreddit);
+            report_error(bot.clone(), dest, rcmd, e).await?;
+            return what_now(bot, dest, my_state, rcmd, false).await;
+        }
+    };
+    // skipped or filtered posts might leave fewer posts than asked
+    let summary = match (&rcmd.search, sent) {
+        (Some(search), 0) => format!("*No new results for \"{}\"*", search.query),
+        (Some(search), _) => format!("*Shown {} results for \"{}\"*", sent, search.query),
+        (None, 0) => format!(
+            "*No new {} posts from {} / {}*",
+            rcmd.view, rcmd.category, rcmd.subreddit
+        ),
+        (None, _) => format!(
+            "*Shown {} {} posts from {} / {}*",
+            sent, rcmd.view, rcmd.category, rcmd.subreddit
+        ),
+    };
+    let mut md = payloads::SendMessage::new(chat_id, summary);
+    md.message_thread_id = dest.topic;
     type Sender = JsonRequest<payloads::SendMessage>;
     let sent = Sender::new(bot.clone(), md.clone().parse_mode(ParseMode::MarkdownV2)).await;
     // If markdown cannot be parsed, send it as raw text
     if sent.is_err() {
         Sender::new(bot.clone(), md.clone()).await?;
     };
+    let subscribable = rcmd.search.is_none();
+    what_now(bot, dest, my_state, rcmd, subscribable).await
+}
+
+// Explain why the posts cannot be shown
+async fn report_error(bot: Bot, dest: Dest, rcmd: &RedditCmd, e: &RedditError) -> HandlerResult {
+    let mut txt = e.to_string();
+    let single = !rcmd.subreddit.contains(['+', ',', '/']);
+    if matches!(e, RedditError::NotFound) && rcmd.search.is_none() && single {
+        let similar = reddit::similar_subreddits(&rcmd.subreddit).await;
+        if !similar.is_empty() {
+            txt.push_str(&format!("\nDid you mean: {}?", similar.join(", ")));
+        }
+    }
+    bot.send_message(dest.chat_id, txt)
+        .in_topic(dest.topic)
+        .await?;
+    Ok(())
+}
+
+async fn what_now(
+    bot: Bot,
+    dest: Dest,
+    my_state: &MyState,
+    rcmd: &RedditCmd,
+    subscribable: bool,
+) -> HandlerResult {
     // select next page or quit
-    let cmd_next = vec![
-        ("Done".to_string(), "Done".to_string()),
-        ("Show another page".to_string(), "Next".to_string()),
+    let mut cmd_next = vec![
+        ("Done".to_string(), Action::Done),
+        ("Show another page".to_string(), Action::Next(rcmd.clone())),
     ];
-    let cmd_next = cmd_next
-        .iter()
-        .map(|cmd| InlineKeyboardButton::callback(cmd.0.to_owned(), cmd.1.to_owned()));
-    let sent = bot
-        .send_message(chat_id, "What now?")
-        .reply_markup(InlineKeyboardMarkup::new([cmd_next]))
+    if subscribable {
+        cmd_next.push(("Subscribe".to_string(), Action::Subscribe(rcmd.clone())));
+    }
+    bot.send_message(dest.chat_id, "What now?")
+        .in_topic(dest.topic)
+        .reply_markup(action_keyboard(my_state, vec![cmd_next]).await?)
         .await?;
-    Ok(Some(sent.id))
+    Ok(())
 }
 
 async fn issue_cmd(
     bot: Bot,
     dialogue: MyDialogue,
-    q: CallbackQuery,
-    tup_state: (Arc<MyState>, RedditCmd, Option<MessageId>),
+    my_state: Arc<MyState>,
+    topic: Topic,
+    mut rcmd: RedditCmd,
 ) -> HandlerResult {
-    let (my_state, rcmd, m_id) = tup_state;
     let chat_id = dialogue.chat_id();
-    clean_buttons(bot.clone(), chat_id, m_id).await?;
-    let tot: u32 = q
-        .data
-        .unwrap_or_else(|| "1".to_string())
-        .parse()
-        .unwrap_or(1);
-    let mut rcmd = RedditCmd { tot, ..rcmd };
     log::info!("{chat_id} {rcmd:?}");
     // send pages and show next/quit menu
-    let url_matches = &my_state.my_conf.url_matches;
-    let prev = send_page(bot.clone(), &mut rcmd, chat_id, url_matches).await?;
-    dialogue
-        .update(State::NextPage {
-            my_state,
-            rcmd,
-            prev,
-        })
-        .await?;
+    let dest = Dest::new(chat_id, topic);
+    send_page(bot, &mut rcmd, dest, &my_state).await?;
+    dialogue.update(State::Start).await?;
     Ok(())
 }
 
-async fn next_page(
-    bot: Bot,
-    dialogue: MyDialogue,
-    q: CallbackQuery,
-    tup_state: (Arc<MyState>, RedditCmd, Option<MessageId>),
-) -> HandlerResult {
-    let (my_state, mut rcmd, m_id) = tup_state;
-    let chat_id = dialogue.chat_id();
-    clean_buttons(bot.clone(), chat_id, m_id).await?;
-    let cmd_next = &q.data.unwrap_or_else(|| "Done".to_string());
-    match cmd_next.as_str() {
-        "Next" => {
-            let url_matches = &my_state.my_conf.url_matches;
-            let prev = send_page(bot, &mut rcmd, chat_id, url_matches).await?;
-            dialogue
-                .update(State::NextPage {
-                    my_state,
-                    rcmd,
-                    prev,
-                })
-                .await?;
+fn shortcut_usage() -> String {
+    let periods: Vec<&str> = Period::iter().map(|p| p.as_query()).collect();
+    format!(
+        "Usage: /hot <subreddit> [tot], /top <subreddit> [period] [tot], \
+         /rising <subreddit> [tot], or /r <subreddit> [view] [period] [tot]\n\
+         where [view] is one of best, hot, new, rising, top, controversial (default hot), \
+         [period] one of {} (default day), and [tot] a number from 1 to 40 (default 5). \
+         E.g.: /top rust week 5",
+        periods.join(", ")
+    )
+}
+
+// The options following the subreddit, in any order; the view can be fixed by the command
+fn parse_shortcut(view: Option<RedReq>, args: &str) -> Option<RedditCmd> {
+    let mut words = args.split_whitespace();
+    let subreddit = reddit::feed_name(words.next()?);
+    let fixed = view.is_some();
+    let mut view = view;
+    let mut period = None;
+    let mut tot = None;
+    for word in words {
+        if let Ok(n) = word.parse::<u32>() {
+            if tot.is_some() || !(1..=40).contains(&n) {
+                return None;
+            }
+            tot = Some(n);
+        } else if let (None, Some(p)) = (period, Period::from_alias(word)) {
+            period = Some(p);
+        } else if let (false, Some(v)) = (fixed, RedReq::from_alias(word)) {
+            view = Some(v);
+        } else {
+            return None;
+        }
+    }
+    let view = match (view.unwrap_or(RedReq::Hot), period) {
+        (RedReq::Top(_), Some(p)) => RedReq::Top(p),
+        (RedReq::Controversial(_), Some(p)) => RedReq::Controversial(p),
+        (_, Some(_)) => return None,
+        (view, None) => view,
+    };
+    Some(RedditCmd {
+        view,
+        subreddit,
+        tot: tot.unwrap_or(5),
+        category: "Custom".to_string(),
+        last_seen: None,
+        search: None,
+    })
+}
+
+// Show a page of posts at once, skipping the menu
+async fn shortcut(
+    bot: Bot,
+    dialogue: MyDialogue,
+    msg: Message,
+    cmd: Command,
+    my_state: Arc<MyState>,
+) -> HandlerResult {
+    // whitelist check
+    let chat_id = msg.chat.id;
+    let wl = &my_state.my_conf.id_whitelist;
+    if !allowed(&chat_id, wl) {
+        reply(&bot, &msg, "Sorry dude, you're not in the whitelist.").await?;
+        return Ok(());
+    }
+    let (view, args) = match cmd {
+        Command::Hot(args) => (Some(RedReq::Hot), args),
+        Command::Top(args) => (Some(RedReq::Top(Period::D)), args),
+        Command::Rising(args) => (Some(RedReq::Rise), args),
+        Command::R(args) => (None, args),
+        _ => return Ok(()),
+    };
+    match parse_shortcut(view, &args) {
+        Some(rcmd) => issue_cmd(bot, dialogue, my_state, topic::of(&msg), rcmd).await,
+        None => {
+            reply(&bot, &msg, shortcut_usage()).await?;
             Ok(())
         }
+    }
+}
+
+// Answer "@bot r/<subreddit> [view] [period]" with posts to share, paged by the listing
+async fn inline_query(bot: Bot, q: InlineQuery, my_state: Arc<MyState>) -> HandlerResult {
+    let page_size = 10;
+    // whitelist check, a user has the same id as their private chat
+    let chat_id = ChatId(q.from.id.0 as i64);
+    let query = q.query.trim();
+    let query = query.strip_prefix("r/").unwrap_or(query);
+    let rcmd = parse_shortcut(None, query).filter(|_| {
+        let wl = &my_state.my_conf.id_whitelist;
+        allowed(&chat_id, wl)
+    });
+    let Some(rcmd) = rcmd else {
+        bot.answer_inline_query(q.id, []).await?;
+        return Ok(());
+    };
+    let mut rcmd = RedditCmd {
+        tot: page_size,
+        last_seen: Some(q.offset).filter(|o| !o.is_empty()),
+        ..rcmd
+    };
+    let results = match reddit::inline_results(&mut rcmd, &my_state, chat_id).await {
+        Ok(results) => results,
+        Err(e) => {
+            log::info!("{chat_id} cannot answer inline query {}: {}", q.query, e);
+            rcmd.last_seen = None;
+            Vec::new()
+        }
+    };
+    // no offset after the last page
+    let next_offset = rcmd.last_seen.unwrap_or_default();
+    bot.answer_inline_query(q.id, results)
+        .next_offset(next_offset)
+        .is_personal(true)
+        .cache_time(60)
+        .await?;
+    Ok(())
+}
+
+fn search_usage() -> String {
+    let sorts: Vec<String> = SearchSort::iter().map(|s| s.to_string()).collect();
+    let periods: Vec<&str> = Period::iter().map(|p| p.as_query()).collect();
+    format!(
+        "Usage: /search <query> [in r/<subreddit>] [sort] [period]\n\
+         where [sort] is one of {}, and [period] one of {}. \
+         E.g.: /search borrow checker in r/rust top year\n\
+         Missing options are chosen via buttons.",
+        sorts.join(", "),
+        periods.join(", ")
+    )
+}
+
+// Split the trailing options from the query, in any order
+fn parse_search(args: &str) -> (Search, Option<String>) {
+    let mut words: Vec<&str> = args.split_whitespace().collect();
+    let mut sort = None;
+    let mut period = None;
+    let mut subreddit = None;
+    loop {
+        let n = words.len();
+        // only "in r/<subreddit>", as "in" is a common word of the queries
+        let sub = match n {
+            0 | 1 => None,
+            _ if subreddit.is_some() || !words[n - 2].eq_ignore_ascii_case("in") => None,
+            _ => words[n - 1].trim_start_matches('/').strip_prefix("r/"),
+        };
+        if let Some(sub) = sub.filter(|s| !s.is_empty()) {
+            subreddit = Some(sub.to_string());
+            words.truncate(n - 2);
+            continue;
+        }
+        let last = match words.last() {
+            Some(last) if n > 1 => last.to_lowercase(),
+            _ => break,
+        };
+        if let (None, Ok(s)) = (sort, SearchSort::from_str(&last)) {
+            sort = Some(s);
+        } else if let (None, Some(p)) = (period, Period::from_query(&last)) {
+            period = Some(p);
+        } else {
+            break;
+        }
+        words.pop();
+    }
+    let query = words.join(" ");
+    let search = Search {
+        query,
+        sort,
+        period,
+    };
+    (search, subreddit)
+}
+
+async fn search(
+    bot: Bot,
+    dialogue: MyDialogue,
+    msg: Message,
+    args: String,
+    my_state: Arc<MyState>,
+) -> HandlerResult {
+    // whitelist check
+    let chat_id = msg.chat.id;
+    let wl = &my_state.my_conf.id_whitelist;
+    if !allowed(&chat_id, wl) {
+        reply(&bot, &msg, "Sorry dude, you're not in the whitelist.").await?;
+        return Ok(());
+    }
+    let (search, subreddit) = parse_search(&args);
+    if search.query.is_empty() {
+        reply(&bot, &msg, search_usage()).await?;
+        return Ok(());
+    }
+    let rcmd = RedditCmd {
+        view: RedReq::Hot,
+        subreddit: subreddit.unwrap_or_default(),
+        tot: 0,
+        category: "Search".to_string(),
+        last_seen: None,
+        search: Some(search),
+    };
+    search_step(bot, dialogue, my_state, topic::of(&msg), rcmd).await
+}
+
+// Ask for the missing search options, then for the number of results
+async fn search_step(
+    bot: Bot,
+    dialogue: MyDialogue,
+    my_state: Arc<MyState>,
+    topic: Topic,
+    rcmd: RedditCmd,
+) -> HandlerResult {
+    let chat_id = dialogue.chat_id();
+    let search = match &rcmd.search {
+        Some(search) => search.clone(),
+        None => return Ok(()),
+    };
+    // each option continues the search with it
+    let with = |search: Search| {
+        let rcmd = RedditCmd {
+            search: Some(search),
+            ..rcmd.clone()
+        };
+        Action::Search(rcmd)
+    };
+    let (txt_msg, opts): (&str, Vec<(String, Action)>) = match search.sort {
+        None => (
+            "Sort the results by:",
+            SearchSort::iter()
+                .map(|sort| {
+                    let opt = Search {
+                        sort: Some(sort),
+                        ..search.clone()
+                    };
+                    (sort.as_query().to_string(), with(opt))
+                })
+                .collect(),
+        ),
+        Some(sort) if sort.has_period() && search.period.is_none() => (
+            "Results from the past:",
+            Period::iter()
+                .map(|period| {
+                    let opt = Search {
+                        period: Some(period),
+                        ..search.clone()
+                    };
+                    (period.as_query().to_string(), with(opt))
+                })
+                .collect(),
+        ),
         _ => {
-            // "Done"
-            dialogue
-                .update(State::Start {
-                    my_state: my_state.clone(),
+            let txt_msg = format!("How many results for \"{}\"?:", search.query);
+            return select_tot_core(bot, dialogue, &my_state, topic, txt_msg, rcmd).await;
+        }
+    };
+    bot.send_message(chat_id, txt_msg)
+        .in_topic(topic)
+        .reply_markup(action_keyboard(&my_state, vec![opts]).await?)
+        .await?;
+    dialogue.update(State::Start).await?;
+    Ok(())
+}
+
+fn subscribe_usage() -> String {
+    let views: Vec<String> = RedReq::groups()
+        .iter()
+        .flatten()
+        .map(|rc| rc.to_string())
+        .collect();
+    format!(
+        "Usage: /subscribe [@channel] <subreddit> <view> <tot> <schedule>\n\
+         where <view> is one of {}, and <schedule> is either \"daily HH:MM\" (UTC) \
+         or \"every N h\". E.g.: /subscribe rust TopD 5 daily 08:30\n\
+         With @channel (or its numeric id), the digest is posted into that channel, \
+         where both you and the bot must be admins.\n\
+         You can also subscribe from the menu, after viewing a page of posts.",
+        views.join(", ")
+    )
+}
+
+async fn subscribe(bot: Bot, msg: Message, args: String, my_state: Arc<MyState>) -> HandlerResult {
+    // whitelist check
+    let chat_id = msg.chat.id;
+    let wl = &my_state.my_conf.id_whitelist;
+    if !allowed(&chat_id, wl) {
+        reply(&bot, &msg, "Sorry dude, you're not in the whitelist.").await?;
+        return Ok(());
+    }
+    if !may_configure(&bot, &my_state, chat_id, msg.from()).await? {
+        reply(&bot, &msg, ADMINS_ONLY).await?;
+        return Ok(());
+    }
+    let args: Vec<&str> = args.split_whitespace().collect();
+    // the digests can be posted into a channel (by @username or -100 id), instead of this chat
+    let (channel, args) = match args.split_first() {
+        Some((arg, rest)) if arg.starts_with('@') || arg.starts_with("-100") => (Some(*arg), rest),
+        _ => (None, args.as_slice()),
+    };
+    let Some((rcmd, sched)) = parse_subscription(args) else {
+        reply(&bot, &msg, subscribe_usage()).await?;
+        return Ok(());
+    };
+    let dest = match channel {
+        None => Dest::new(chat_id, topic::of(&msg)),
+        Some(channel) => match channel_dest(&bot, channel, msg.from()).await {
+            Ok(channel_id) => Dest::new(channel_id, None),
+            Err(txt) => {
+                reply(&bot, &msg, txt).await?;
+                return Ok(());
+            }
+        },
+    };
+    let txt = add_subscription(chat_id, dest, &my_state, &rcmd, &sched).await?;
+    reply(&bot, &msg, txt).await?;
+    Ok(())
+}
+
+// Subreddit, view, number of posts and schedule of a subscription
+fn parse_subscription(args: &[&str]) -> Option<(RedditCmd, Schedule)> {
+    if args.len() < 4 {
+        return None;
+    }
+    let view = RedReq::from_str(args[1]).ok()?;
+    let tot = args[2]
+        .parse::<u32>()
+        .ok()
+        .filter(|tot| (1..=40).contains(tot))?;
+    let sched = Schedule::from_str(&args[3..].join(" ")).ok()?;
+    let rcmd = RedditCmd {
+        view,
+        subreddit: reddit::feed_name(args[0]),
+        tot,
+        category: "Custom".to_string(),
+        last_seen: None,
+        search: None,
+    };
+    Some((rcmd, sched))
+}
+
+// The channel to post digests into, given by @username or id: the user and the bot
+// must be admins there. Otherwise, the reason to tell the user
+async fn channel_dest(bot: &Bot, channel: &str, user: Option<&User>) -> Result<ChatId, String> {
+    let recipient = match channel.parse::<i64>() {
+        Ok(id) => Recipient::Id(ChatId(id)),
+        Err(_) => Recipient::ChannelUsername(channel.to_string()),
+    };
+    let chat = match bot.get_chat(recipient).await {
+        Ok(chat) if chat.is_channel() => chat,
+        _ => return Err(format!("I cannot find the channel {}.", channel)),
+    };
+    // the bot can only check the members of the channels it administers
+    let me = bot.get_me().await.map_err(|e| e.to_string())?;
+    let can_post = match bot.get_chat_member(chat.id, me.id).await {
+        Ok(member) => member.kind.can_post_messages(),
+        Err(_) => false,
+    };
+    if !can_post {
+        let txt = format!("Please make me an admin of {}, allowed to post.", channel);
+        return Err(txt);
+    }
+    let is_admin = match user {
+        Some(user) => match bot.get_chat_member(chat.id, user.id).await {
+            Ok(member) => member.kind.is_privileged(),
+            Err(_) => false,
+        },
+        None => false,
+    };
+    if !is_admin {
+        return Err(format!("Only the admins of {} can subscribe it.", channel));
+    }
+    Ok(chat.id)
+}
+
+// Save the subscription, returning the confirmation for the user
+async fn add_subscription(
+    chat_id: ChatId,
+    dest: Dest,
+    my_state: &MyState,
+    rcmd: &RedditCmd,
+    sched: &Schedule,
+) -> anyhow::Result<String> {
+    let next_run = sched.next_run(scheduler::now());
+    let schedule = sched.to_string();
+    let id = persist::insert_subscription(&my_state.db, chat_id, dest, rcmd, &schedule, next_run)
+        .await?;
+    log::info!("{chat_id} subscribed #{id}: {rcmd:?} {sched} {dest:?}");
+    let mut txt = format!(
+        "Subscription #{} saved: {} {} posts from {}, {} (UTC)",
+        id, rcmd.tot, rcmd.view, rcmd.subreddit, sched
+    );
+    if dest.chat_id != chat_id {
+        txt.push_str(&format!(", posted into channel {}", dest.chat_id));
+    }
+    txt.push('.');
+    Ok(txt)
+}
+
+async fn select_schedule(
+    bot: Bot,
+    dialogue: MyDialogue,
+    my_state: Arc<MyState>,
+    topic: Topic,
+    rcmd: RedditCmd,
+) -> HandlerResult {
+    let chat_id = dialogue.chat_id();
+    let scheds = [
+        ["every 1h", "every 3h", "every 6h", "every 12h"],
+        ["daily 07:00", "daily 12:00", "daily 18:00", "daily 22:00"],
+    ];
+    let rcmd = RedditCmd {
+        last_seen: None,
+        ..rcmd
+    };
+    let buttons = scheds
+        .iter()
+        .map(|r| {
+            r.iter()
+                .map(|sched| {
+                    let action = Action::Schedule(rcmd.clone(), sched.to_string());
+                    (sched.to_string(), action)
                 })
-                .await?;
-            select_category(bot, dialogue, my_state).await
+                .collect()
+        })
+        .collect();
+    let txt_msg = format!(
+        "How often should I send you {} {} posts from {}? (times are UTC)",
+        rcmd.tot, rcmd.view, rcmd.subreddit
+    );
+    bot.send_message(chat_id, txt_msg)
+        .in_topic(topic)
+        .reply_markup(action_keyboard(&my_state, buttons).await?)
+        .await?;
+    dialogue.update(State::Start).await?;
+    Ok(())
+}
+
+async fn save_subscription(
+    bot: Bot,
+    dialogue: MyDialogue,
+    my_state: Arc<MyState>,
+    topic: Topic,
+    tup_state: (RedditCmd, String),
+) -> HandlerResult {
+    let (rcmd, sched) = tup_state;
+    let chat_id = dialogue.chat_id();
+    let dest = Dest::new(chat_id, topic);
+    let sched = Schedule::from_str(&sched)?;
+    let txt = add_subscription(chat_id, dest, &my_state, &rcmd, &sched).await?;
+    bot.send_message(chat_id, txt).in_topic(topic).await?;
+    // back to the next/quit menu
+    what_now(bot, dest, &my_state, &rcmd, true).await?;
+    dialogue.update(State::Start).await?;
+    Ok(())
+}
+
+async fn subscriptions(
+    bot: Bot,
+    dialogue: MyDialogue,
+    msg: Message,
+    my_state: Arc<MyState>,
+) -> HandlerResult {
+    // whitelist check
+    let chat_id = msg.chat.id;
+    let wl = &my_state.my_conf.id_whitelist;
+    if !allowed(&chat_id, wl) {
+        reply(&bot, &msg, "Sorry dude, you're not in the whitelist.").await?;
+        return Ok(());
+    }
+    if !may_configure(&bot, &my_state, chat_id, msg.from()).await? {
+        reply(&bot, &msg, ADMINS_ONLY).await?;
+        return Ok(());
+    }
+    show_subscriptions(bot, dialogue, my_state, topic::of(&msg)).await
+}
+
+async fn show_subscriptions(
+    bot: Bot,
+    dialogue: MyDialogue,
+    my_state: Arc<MyState>,
+    topic: Topic,
+) -> HandlerResult {
+    let chat_id = dialogue.chat_id();
+    let subs = persist::fetch_subscriptions(&my_state.db, chat_id).await?;
+    if subs.is_empty() {
+        bot.send_message(
+            chat_id,
+            "You have no subscriptions. Type /subscribe to see how to add one.",
+        )
+        .in_topic(topic)
+        .await?;
+        return Ok(());
+    }
+    let mut txt_msg = "Your subscriptions (times are UTC):".to_string();
+    for sub in &subs {
+        txt_msg.push_str(&format!(
+            "\n#{}: {} {} posts from {}, {}",
+            sub.id, sub.rcmd.tot, sub.rcmd.view, sub.rcmd.subreddit, sub.schedule
+        ));
+        if sub.dest.chat_id != chat_id {
+            txt_msg.push_str(&format!(" → channel {}", sub.dest.chat_id));
+        }
+    }
+    let mut buttons: Vec<Vec<(String, Action)>> = subs
+        .chunks(3)
+        .map(|r| {
+            r.iter()
+                .map(|sub| (format!("Remove #{}", sub.id), Action::Unsubscribe(sub.id)))
+                .collect()
+        })
+        .collect();
+    buttons.push(vec![("Done".to_string(), Action::Done)]);
+    bot.send_message(chat_id, txt_msg)
+        .in_topic(topic)
+        .reply_markup(action_keyboard(&my_state, buttons).await?)
+        .await?;
+    dialogue.update(State::Start).await?;
+    Ok(())
+}
+
+async fn remove_subscription(
+    bot: Bot,
+    dialogue: MyDialogue,
+    my_state: Arc<MyState>,
+    topic: Topic,
+    id: i64,
+) -> HandlerResult {
+    let chat_id = dialogue.chat_id();
+    let num = persist::del_subscription(&my_state.db, chat_id, id).await?;
+    let txt = match num {
+        0 => format!("Subscription #{} not found.", id),
+        _ => format!("Subscription #{} has been removed.", id),
+    };
+    bot.send_message(chat_id, txt).in_topic(topic).await?;
+    show_subscriptions(bot, dialogue, my_state, topic).await
+}
+
+async fn caption(bot: Bot, msg: Message, args: String, my_state: Arc<MyState>) -> HandlerResult {
+    // whitelist check
+    let chat_id = msg.chat.id;
+    let wl = &my_state.my_conf.id_whitelist;
+    if !allowed(&chat_id, wl) {
+        reply(&bot, &msg, "Sorry dude, you're not in the whitelist.").await?;
+        return Ok(());
+    }
+    let template = args.trim();
+    if !template.is_empty() && !may_configure(&bot, &my_state, chat_id, msg.from()).await? {
+        reply(&bot, &msg, ADMINS_ONLY).await?;
+        return Ok(());
+    }
+    match template {
+        "" => {
+            let cur = persist::get_setting(&my_state.db, chat_id, "caption")
+                .await?
+                .unwrap_or_else(|| my_state.my_conf.caption_template.clone());
+            let fields: Vec<String> = caption::FIELDS
+                .iter()
+                .map(|f| format!("{{{}}}", f))
+                .collect();
+            reply(
+                &bot,
+                &msg,
+                format!(
+                    "Your caption template is:\n{}\n\n\
+                     Usage: /caption <template>, or /caption default to restore the default one. \
+                     Templates can use Telegram HTML tags (e.g., <b>{{title}}</b>) and the fields: {}",
+                    cur,
+                    fields.join(", ")
+                ),
+            )
+            .await?;
+        }
+        "default" => {
+            persist::del_setting(&my_state.db, chat_id, "caption").await?;
+            reply(
+                &bot,
+                &msg,
+                "The default caption template has been restored.",
+            )
+            .await?;
+        }
+        _ => {
+            // check the template with a preview
+            let preview = caption::render(template, &caption::sample_fields(), caption::MAX_LEN);
+            let sent = reply(&bot, &msg, format!("Preview:\n{}", preview))
+                .parse_mode(ParseMode::Html)
+                .disable_web_page_preview(true)
+                .await;
+            match sent {
+                Ok(_) => {
+                    persist::set_setting(&my_state.db, chat_id, "caption", template).await?;
+                    reply(&bot, &msg, "Your caption template has been saved.").await?;
+                }
+                Err(e) => {
+                    reply(&bot, &msg, format!("Invalid caption template: {}", e)).await?;
+                }
+            }
         }
     }
+    Ok(())
+}
+
+fn filters_usage() -> String {
+    format!(
+        "Usage: /filters [#<subscription>] <rule> [values], e.g.:\n\
+         /filters min_score 100\n\
+         /filters #3 exclude_keywords politics, election\n\
+         /filters #3 exclude_keywords (to clear a rule)\n\
+         /filters reset\n\
+         Rules: {}. Keywords are regexes matched against the titles, \
+         and multiple values are separated by commas.",
+        filters::RULES.join(", ")
+    )
+}
+
+async fn filters(bot: Bot, msg: Message, args: String, my_state: Arc<MyState>) -> HandlerResult {
+    // whitelist check
+    let chat_id = msg.chat.id;
+    let wl = &my_state.my_conf.id_whitelist;
+    if !allowed(&chat_id, wl) {
+        reply(&bot, &msg, "Sorry dude, you're not in the whitelist.").await?;
+        return Ok(());
+    }
+    let args = args.trim();
+    if !args.is_empty() && !may_configure(&bot, &my_state, chat_id, msg.from()).await? {
+        reply(&bot, &msg, ADMINS_ONLY).await?;
+        return Ok(());
+    }
+    // filters of a subscription, or of the whole chat
+    let (sub_id, args) = match args.strip_prefix('#') {
+        Some(rest) => {
+            let (id, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+            let subs = persist::fetch_subscriptions(&my_state.db, chat_id).await?;
+            match id.parse::<i64>() {
+                Ok(id) if subs.iter().any(|s| s.id == id) => (Some(id), rest.trim()),
+                _ => {
+                    let txt = format!("There is no subscription #{}, see /subscriptions.", id);
+                    reply(&bot, &msg, txt).await?;
+                    return Ok(());
+                }
+            }
+        }
+        None => (None, args),
+    };
+    let target = match sub_id {
+        Some(id) => format!("subscription #{}", id),
+        None => "this chat".to_string(),
+    };
+    let mut rules = persist::get_filters(&my_state.db, chat_id, sub_id).await?;
+    let (rule, values) = args.split_once(' ').unwrap_or((args, ""));
+    let values: Vec<&str> = values
+        .split(',')
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+        .collect();
+    let res = match rule {
+        "" => {
+            let txt = format!("Filters of {}:\n{}\n\n{}", target, rules, filters_usage());
+            reply(&bot, &msg, txt).await?;
+            return Ok(());
+        }
+        "reset" => {
+            rules = Filters::default();
+            Ok(())
+        }
+        _ => rules.set(rule, &values),
+    };
+    match res {
+        Ok(()) => {
+            persist::set_filters(&my_state.db, chat_id, sub_id, &rules).await?;
+            let txt = format!("Filters of {} updated:\n{}", target, rules);
+            reply(&bot, &msg, txt).await?;
+        }
+        Err(e) => {
+            reply(&bot, &msg, format!("{}\n\n{}", e, filters_usage())).await?;
+        }
+    }
+    Ok(())
+}
+
+fn watch_usage() -> String {
+    "Usage: /watch <regex> in <subreddits>, e.g.:\n\
+     /watch release|announc in rust+programming\n\
+     New posts whose title or text matches the regex are sent as soon as they are found. \
+     Use /unwatch <number> to stop a notification."
+        .to_string()
+}
+
+async fn watch(bot: Bot, msg: Message, args: String, my_state: Arc<MyState>) -> HandlerResult {
+    // whitelist check
+    let chat_id = msg.chat.id;
+    let wl = &my_state.my_conf.id_whitelist;
+    if !allowed(&chat_id, wl) {
+        reply(&bot, &msg, "Sorry dude, you're not in the whitelist.").await?;
+        return Ok(());
+    }
+    let args = args.trim();
+    if args.is_empty() {
+        let watches = persist::fetch_watches(&my_state.db, Some(chat_id)).await?;
+        let mut txt = match watches.is_empty() {
+            true => "You are not watching anything.".to_string(),
+            false => "Your notifications:".to_string(),
+        };
+        for w in watches {
+            txt.push_str(&format!(
+                "\n#{}: \"{}\" in {}",
+                w.id, w.pattern, w.subreddit
+            ));
+        }
+        txt.push_str(&format!("\n\n{}", watch_usage()));
+        reply(&bot, &msg, txt).await?;
+        return Ok(());
+    }
+    if !may_configure(&bot, &my_state, chat_id, msg.from()).await? {
+        reply(&bot, &msg, ADMINS_ONLY).await?;
+        return Ok(());
+    }
+    let (pattern, subreddit) = match args.rsplit_once(" in ") {
+        Some((p, s)) => (
+            p.trim(),
+            reddit::multireddit(s.trim().trim_start_matches("r/")),
+        ),
+        None => (args, "".to_string()),
+    };
+    if pattern.is_empty() || subreddit.is_empty() {
+        reply(&bot, &msg, watch_usage()).await?;
+        return Ok(());
+    }
+    if let Err(e) = watcher::compile(pattern) {
+        reply(&bot, &msg, format!("Invalid regex: {}", e)).await?;
+        return Ok(());
+    }
+    let topic = topic::of(&msg);
+    let now = scheduler::now();
+    let id = persist::insert_watch(&my_state.db, chat_id, topic, pattern, &subreddit, now).await?;
+    let mins = my_state.my_conf.watch_interval_mins;
+    let txt = format!(
+        "Watching \"{}\" in {} (#{}), new posts are checked every {} minutes.",
+        pattern, subreddit, id, mins
+    );
+    reply(&bot, &msg, txt).await?;
+    Ok(())
+}
+
+async fn unwatch(bot: Bot, msg: Message, args: String, my_state: Arc<MyState>) -> HandlerResult {
+    // whitelist check
+    let chat_id = msg.chat.id;
+    let wl = &my_state.my_conf.id_whitelist;
+    if !allowed(&chat_id, wl) {
+        reply(&bot, &msg, "Sorry dude, you're not in the whitelist.").await?;
+        return Ok(());
+    }
+    if !may_configure(&bot, &my_state, chat_id, msg.from()).await? {
+        reply(&bot, &msg, ADMINS_ONLY).await?;
+        return Ok(());
+    }
+    let id = args.trim().trim_start_matches('#');
+    let num = match id.parse::<i64>() {
+        Ok(id) => persist::del_watch(&my_state.db, chat_id, id).await?,
+        Err(_) => 0,
+    };
+    let txt = match num {
+        0 => format!("There is no notification #{}, see /watch.", id),
+        _ => format!("Notification #{} has been removed.", id),
+    };
+    reply(&bot, &msg, txt).await?;
+    Ok(())
+}
+
+async fn settings(
+    bot: Bot,
+    dialogue: MyDialogue,
+    msg: Message,
+    my_state: Arc<MyState>,
+) -> HandlerResult {
+    // whitelist check
+    let chat_id = msg.chat.id;
+    let wl = &my_state.my_conf.id_whitelist;
+    if !allowed(&chat_id, wl) {
+        reply(&bot, &msg, "Sorry dude, you're not in the whitelist.").await?;
+        return Ok(());
+    }
+    show_settings(bot, dialogue, my_state, topic::of(&msg)).await
+}
+
+async fn show_settings(
+    bot: Bot,
+    dialogue: MyDialogue,
+    my_state: Arc<MyState>,
+    topic: Topic,
+) -> HandlerResult {
+    let chat_id = dialogue.chat_id();
+    let mut buttons = Vec::new();
+    for (key, descr, values) in SETTINGS {
+        // there are no admins in private chats
+        if key == "admin_only" && chat_id.is_user() {
+            continue;
+        }
+        let val = persist::get_setting(&my_state.db, chat_id, key)
+            .await?
+            .unwrap_or_else(|| default_setting(&my_state, key, values));
+        let txt = format!("{}: {}", descr, val);
+        buttons.push(vec![(txt, Action::Setting(key.to_string()))]);
+    }
+    buttons.push(vec![("Done".to_string(), Action::Done)]);
+    bot.send_message(chat_id, "Your settings (tap to change):")
+        .in_topic(topic)
+        .reply_markup(action_keyboard(&my_state, buttons).await?)
+        .await?;
+    dialogue.update(State::Start).await?;
+    Ok(())
+}
+
+async fn change_setting(
+    bot: Bot,
+    dialogue: MyDialogue,
+    my_state: Arc<MyState>,
+    topic: Topic,
+    key: String,
+) -> HandlerResult {
+    let chat_id = dialogue.chat_id();
+    if let Some((key, _, values)) = SETTINGS.iter().find(|(k, _, _)| *k == key) {
+        // cycle through the possible values
+        let val = persist::get_setting(&my_state.db, chat_id, key)
+            .await?
+            .unwrap_or_else(|| default_setting(&my_state, key, values));
+        let pos = values.iter().position(|v| *v == val).unwrap_or(0);
+        let val = values[(pos + 1) % values.len()];
+        persist::set_setting(&my_state.db, chat_id, key, val).await?;
+    }
+    show_settings(bot, dialogue, my_state, topic).await
+}
+
+// Send a page of the comments of a post, with a button for the next one
+async fn show_comments(
+    bot: Bot,
+    chat_id: ChatId,
+    my_state: Arc<MyState>,
+    topic: Topic,
+    tup_state: (String, usize),
+) -> HandlerResult {
+    let (post_id, page) = tup_state;
+    let mut pages = reddit::comment_pages(&post_id).await;
+    if page >= pages.len() {
+        bot.send_message(chat_id, "No comments to show.")
+            .in_topic(topic)
+            .await?;
+        return Ok(());
+    }
+    let more = page + 1 < pages.len();
+    let req = bot
+        .send_message(chat_id, pages.swap_remove(page))
+        .in_topic(topic)
+        .parse_mode(ParseMode::Html)
+        .disable_web_page_preview(true);
+    if !more {
+        req.await?;
+        return Ok(());
+    }
+    let action = Action::Comments(post_id, page + 1);
+    let buttons = vec![vec![("Next page".to_string(), action)]];
+    req.reply_markup(action_keyboard(&my_state, buttons).await?)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> (String, Option<SearchSort>, Option<Period>, Option<String>) {
+        let (search, subreddit) = parse_search(args);
+        (search.query, search.sort, search.period, subreddit)
+    }
+
+    #[test]
+    fn search_query_only() {
+        let query = "things to do in paris";
+        assert_eq!(parse(query), (query.to_string(), None, None, None));
+        assert_eq!(parse("new"), ("new".to_string(), None, None, None));
+        assert_eq!(parse(""), ("".to_string(), None, None, None));
+    }
+
+    #[test]
+    fn search_in_subreddit() {
+        let rust = Some("rust".to_string());
+        let query = "borrow checker".to_string();
+        assert_eq!(
+            parse("borrow checker in r/rust"),
+            (query.clone(), None, None, rust.clone())
+        );
+        assert_eq!(
+            parse("borrow checker IN /r/rust"),
+            (query.clone(), None, None, rust.clone())
+        );
+        // a bare "in r/" is part of the query
+        assert_eq!(
+            parse("borrow checker in r/"),
+            ("borrow checker in r/".to_string(), None, None, None)
+        );
+    }
+
+    #[test]
+    fn search_options_with_subreddit() {
+        let rust = Some("rust".to_string());
+        let query = "borrow checker".to_string();
+        let expected = (query, Some(SearchSort::Top), Some(Period::Y), rust);
+        assert_eq!(parse("borrow checker in r/rust top year"), expected);
+        assert_eq!(parse("borrow checker in r/rust year top"), expected);
+        assert_eq!(parse("borrow checker top in r/rust year"), expected);
+        assert_eq!(parse("borrow checker top year in r/rust"), expected);
+    }
+
+    #[test]
+    fn search_options_once() {
+        assert_eq!(
+            parse("top top"),
+            ("top".to_string(), Some(SearchSort::Top), None, None)
+        );
+        assert_eq!(
+            parse("week in paris new"),
+            (
+                "week in paris".to_string(),
+                Some(SearchSort::New),
+                None,
+                None
+            )
+        );
+        assert_eq!(
+            parse("hot day week"),
+            ("hot day".to_string(), None, Some(Period::W), None)
+        );
+    }
+
+    #[test]
+    fn subscription_args() {
+        let (rcmd, sched) = parse_subscription(&["rust", "TopD", "5", "every", "6", "h"]).unwrap();
+        assert_eq!((rcmd.subreddit.as_str(), rcmd.tot), ("rust", 5));
+        assert_eq!(sched, Schedule::from_str("every 6h").unwrap());
+        assert!(parse_subscription(&["rust", "TopD", "0", "daily", "08:30"]).is_none());
+        assert!(parse_subscription(&["rust", "TopD", "5"]).is_none());
+    }
+
+    fn shortcut(view: Option<RedReq>, args: &str) -> Option<(RedReq, String, u32)> {
+        parse_shortcut(view, args).map(|r| (r.view, r.subreddit, r.tot))
+    }
+
+    #[test]
+    fn shortcut_views_and_periods() {
+        let top = Some(RedReq::Top(Period::D));
+        let rust = |view, tot| Some((view, "rust".to_string(), tot));
+        assert_eq!(
+            shortcut(top.clone(), "rust"),
+            rust(RedReq::Top(Period::D), 5)
+        );
+        assert_eq!(
+            shortcut(top.clone(), "rust week 7"),
+            rust(RedReq::Top(Period::W), 7)
+        );
+        assert_eq!(
+            shortcut(top.clone(), "rust 7 w"),
+            rust(RedReq::Top(Period::W), 7)
+        );
+        assert_eq!(
+            shortcut(top.clone(), "rust today"),
+            rust(RedReq::Top(Period::D), 5)
+        );
+        assert_eq!(shortcut(None, "rust"), rust(RedReq::Hot, 5));
+        assert_eq!(shortcut(None, "rust rising 3"), rust(RedReq::Rise, 3));
+        assert_eq!(
+            shortcut(None, "rust controversial y"),
+            rust(RedReq::Controversial(Period::Y), 5)
+        );
+        assert_eq!(shortcut(None, "rust TopW"), rust(RedReq::Top(Period::W), 5));
+        // the subreddit comes first
+        assert_eq!(shortcut(top, "w rust 5"), None);
+        // the view of the command is fixed, and only Top and Cont have periods
+        assert_eq!(shortcut(Some(RedReq::Hot), "rust new"), None);
+        assert_eq!(shortcut(Some(RedReq::Hot), "rust week"), None);
+        assert_eq!(shortcut(None, "rust new week"), None);
+        assert_eq!(shortcut(None, "rust week week"), None);
+    }
+
+    #[test]
+    fn shortcut_feeds() {
+        let hot = Some(RedReq::Hot);
+        let feed = |name: &str| Some((RedReq::Hot, name.to_string(), 5));
+        assert_eq!(shortcut(hot.clone(), "u/spez"), feed("u/spez"));
+        assert_eq!(shortcut(hot.clone(), "/user/spez"), feed("u/spez"));
+        assert_eq!(shortcut(hot.clone(), "rust+golang"), feed("rust+golang"));
+        assert_eq!(shortcut(hot, ""), None);
+    }
+
+    #[test]
+    fn shortcut_counts() {
+        let hot = Some(RedReq::Hot);
+        let rust = |tot| Some((RedReq::Hot, "rust".to_string(), tot));
+        assert_eq!(shortcut(hot.clone(), "rust 1"), rust(1));
+        assert_eq!(shortcut(hot.clone(), "rust 40"), rust(40));
+        assert_eq!(shortcut(hot.clone(), "rust 0"), None);
+        assert_eq!(shortcut(hot.clone(), "rust 41"), None);
+        assert_eq!(shortcut(hot.clone(), "rust -3"), None);
+        assert_eq!(shortcut(hot.clone(), "rust 5 6"), None);
+        assert_eq!(shortcut(hot, "rust five"), None);
+    }
 }
@@ -0,0 +1,60 @@
+use teloxide::payloads::{
+    SendAnimation, SendAudio, SendDocument, SendMediaGroup, SendMessage, SendPhoto, SendVideo,
+};
+use teloxide::requests::{HasPayload, JsonRequest, MultipartRequest};
+use teloxide::types::{CallbackQuery, ChatId, Message, MessageKind};
+
+// Forum topic (message thread) of a supergroup, None outside forums
+pub type Topic = Option<i32>;
+
+pub fn of(msg: &Message) -> Topic {
+    match &msg.kind {
+        MessageKind::Common(common) if common.is_topic_message => msg.thread_id,
+        _ => None,
+    }
+}
+
+pub fn of_query(q: &CallbackQuery) -> Topic {
+    q.message.as_ref().and_then(of)
+}
+
+// Where messages are sent: a chat, or one of its forum topics
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Dest {
+    pub chat_id: ChatId,
+    pub topic: Topic,
+}
+
+impl Dest {
+    pub fn new(chat_id: ChatId, topic: Topic) -> Dest {
+        Dest { chat_id, topic }
+    }
+}
+
+// Requests which can be sent to a forum topic
+pub trait InTopic {
+    fn in_topic(self, topic: Topic) -> Self;
+}
+
+macro_rules! impl_in_topic {
+    ($($req:ident<$payload:ident>),*) => {
+        $(
+            impl InTopic for $req<$payload> {
+                fn in_topic(mut self, topic: Topic) -> Self {
+                    self.payload_mut().message_thread_id = topic;
+                    self
+                }
+            }
+        )*
+    };
+}
+
+impl_in_topic!(
+    JsonRequest<SendMessage>,
+    MultipartRequest<SendPhoto>,
+    MultipartRequest<SendAnimation>,
+    MultipartRequest<SendVideo>,
+    MultipartRequest<SendAudio>,
+    MultipartRequest<SendDocument>,
+    MultipartRequest<SendMediaGroup>
+);
@@ -0,0 +1,104 @@
+use crate::{ImageStep, VideoStep};
+use std::fs;
+use tokio::process::Command;
+
+pub async fn ffmpeg(args: &[&str]) -> bool {
+    let mut all_args = vec!["-y", "-loglevel", "error"];
+    all_args.extend(args);
+    match Command::new("ffmpeg").args(all_args).status().await {
+        Ok(status) => status.success(),
+        Err(e) => {
+            log::info!("Cannot run ffmpeg: {}", e);
+            false
+        }
+    }
+}
+
+async fn duration(f: &str) -> Option<f64> {
+    let args = [
+        "-v",
+        "error",
+        "-show_entries",
+        "format=duration",
+        "-of",
+        "csv=p=0",
+        f,
+    ];
+    let out = Command::new("ffprobe").args(args).output().await.ok()?;
+    String::from_utf8_lossy(&out.stdout).trim().parse().ok()
+}
+
+// Downscale and recompress an image as JPEG
+pub async fn shrink_image(f: &str, step: &ImageStep) -> Option<String> {
+    let out = format!("{}.{}.jpg", f, step.max_side);
+    let scale = format!(
+        "scale='min(iw,{m})':'min(ih,{m})':force_original_aspect_ratio=decrease",
+        m = step.max_side
+    );
+    let quality = step.quality.to_string();
+    let args = [
+        "-i",
+        f,
+        "-vf",
+        &scale,
+        "-q:v",
+        &quality,
+        "-frames:v",
+        "1",
+        &out,
+    ];
+    if ffmpeg(&args).await {
+        Some(out)
+    } else {
+        let _ = fs::remove_file(&out);
+        None
+    }
+}
+
+// Re-encode a video with the first step of the ladder producing a file within max_size
+pub async fn shrink_video(f: &str, max_size: u64, ladder: &[VideoStep]) -> Option<String> {
+    let duration = duration(f).await?;
+    for step in ladder {
+        let est_size = (step.video_kbps + step.audio_kbps) as f64 * 125.0 * duration;
+        if est_size > 0.95 * max_size as f64 {
+            continue;
+        }
+        log::info!("Re-encoding {} at {}p", f, step.height);
+        let out = format!("{}.{}p.mp4", f, step.height);
+        let scale = format!("scale=-2:'min(ih,{})'", step.height);
+        let vrate = format!("{}k", step.video_kbps);
+        let arate = format!("{}k", step.audio_kbps);
+        let bufsize = format!("{}k", 2 * step.video_kbps);
+        let args = [
+            "-i",
+            f,
+            "-vf",
+            &scale,
+            "-c:v",
+            "libx264",
+            "-preset",
+            "veryfast",
+            "-b:v",
+            &vrate,
+            "-maxrate",
+            &vrate,
+            "-bufsize",
+            &bufsize,
+            "-c:a",
+            "aac",
+            "-b:a",
+            &arate,
+            "-movflags",
+            "+faststart",
+            &out,
+        ];
+        let done = ffmpeg(&args).await;
+        match fs::metadata(&out) {
+            Ok(md) if done && md.len() <= max_size => return Some(out),
+            _ => {
+                let _ = fs::remove_file(&out);
+            }
+        }
+    }
+    None
+}
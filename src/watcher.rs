@@ -0,0 +1,87 @@
+use crate::persist::{self, Watch};
+use crate::reddit;
+use crate::scheduler;
+use crate::telegram;
+use crate::topic::{Dest, InTopic};
+use crate::{MyState, NsfwPolicy};
+use anyhow::Result;
+use regex::{Regex, RegexBuilder};
+use std::sync::Arc;
+use std::time::Duration;
+use teloxide::prelude::*;
+
+pub fn default_interval() -> u64 {
+    10
+}
+
+// Patterns are matched case-insensitively
+pub fn compile(pattern: &str) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(pattern).case_insensitive(true).build()
+}
+
+// Background task notifying the new posts matching the watches
+pub async fn run(bot: Bot, my_state: Arc<MyState>) {
+    let mins = my_state.my_conf.watch_interval_mins.max(1);
+    let mut ticker = tokio::time::interval(Duration::from_secs(60 * mins));
+    loop {
+        ticker.tick().await;
+        let watches = match persist::fetch_watches(&my_state.db, None).await {
+            Ok(watches) => watches,
+            Err(e) => {
+                log::error!("Cannot fetch watches: {}", e);
+                continue;
+            }
+        };
+        for watch in watches {
+            // the chat might have been removed from the whitelist since
+            if !telegram::allowed(&watch.chat_id, &my_state.my_conf.id_whitelist) {
+                continue;
+            }
+            if let Err(e) = check(&bot, &watch, &my_state).await {
+                log::error!("Cannot check watch {}: {}", watch.id, e);
+            }
+        }
+    }
+}
+
+async fn check(bot: &Bot, watch: &Watch, my_state: &MyState) -> Result<()> {
+    let max_posts = 100;
+    let re = compile(&watch.pattern)?;
+    let nsfw = reddit::nsfw_policy(my_state, watch.chat_id).await?;
+    let posts = reddit::new_posts(&watch.subreddit, max_posts).await?;
+    // oldest first
+    for post in posts.into_iter().rev() {
+        let data = &post.data.base;
+        // ignore the posts published before the watch was created, or the last notified one:
+        // the seen posts are forgotten after a while
+        let created = data.created_utc as i64;
+        if data.stickied || created < watch.last_post_at {
+            continue;
+        }
+        if !re.is_match(&data.title) && !re.is_match(&data.selftext) {
+            continue;
+        }
+        let sensitive = post.data.is_sensitive();
+        if nsfw == NsfwPolicy::Hide && sensitive {
+            continue;
+        }
+        let keys = [format!("watch{}:{}", watch.id, data.name)];
+        if persist::is_seen(&my_state.db, watch.chat_id, &keys).await? {
+            continue;
+        }
+        // mark it first, so that a failing post is not notified in a loop
+        persist::mark_seen(&my_state.db, watch.chat_id, &keys, scheduler::now()).await?;
+        persist::set_watch_mark(&my_state.db, watch.id, created).await?;
+        let header = format!(
+            "\u{1F514} New post matching \"{}\" in r/{}:",
+            watch.pattern, data.subreddit
+        );
+        let dest = Dest::new(watch.chat_id, watch.topic);
+        bot.send_message(dest.chat_id, header)
+            .in_topic(dest.topic)
+            .await?;
+        let blur = nsfw == NsfwPolicy::Blur && sensitive;
+        reddit::send_post(post, bot.clone(), watch.chat_id, dest, my_state, blur).await?;
+    }
+    Ok(())
+}